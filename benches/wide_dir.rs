@@ -0,0 +1,83 @@
+//! Times scans of a single wide directory, counting the allocations made
+//!
+//! Run with `cargo bench`. The directory is made in the temp dir, holding
+//! `WIDTH` empty files, and removed once finished.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    time::{Duration, Instant},
+};
+
+use dustat::du::{Du, DuSource, mt, st};
+
+const WIDTH: usize = 50_000;
+const RUNS: usize = 10;
+
+/// The system allocator, counting each allocation made through it
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+// SAFETY: every call is passed straight to the system allocator
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Relaxed);
+        // SAFETY: upheld by the caller
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: upheld by the caller
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Relaxed);
+        // SAFETY: upheld by the caller
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("dustat-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for i in 0..WIDTH {
+        std::fs::File::create(dir.join(format!("file-{i:06}"))).unwrap();
+    }
+
+    bench("st", &dir, st::Source::default);
+    bench("mt", &dir, mt::Source::default);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Scans `dir` with a new source `RUNS` times, writing the median time and allocations
+fn bench<P: DuSource>(name: &str, dir: &Path, source: impl Fn() -> P) {
+    let mut times = Vec::with_capacity(RUNS);
+    let mut allocs = Vec::with_capacity(RUNS);
+    for _ in 0..RUNS {
+        let mut du = Du::new(source());
+        let before = ALLOCS.load(Relaxed);
+        let start = Instant::now();
+        du.begin(dir);
+        let read = du.read(&mut |_, _| true);
+        times.push(start.elapsed());
+        allocs.push(ALLOCS.load(Relaxed) - before);
+        assert_eq!(read, WIDTH);
+    }
+    times.sort();
+    allocs.sort();
+
+    let (time, allocs) = (times[RUNS / 2], allocs[RUNS / 2]);
+    let per_entry = |d: Duration| d.as_nanos() as f64 / WIDTH as f64;
+    println!(
+        "{name}: {time:?} ({:.0} ns/entry), {allocs} allocations ({:.2}/entry)",
+        per_entry(time),
+        allocs as f64 / WIDTH as f64,
+    );
+}
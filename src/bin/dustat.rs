@@ -0,0 +1,226 @@
+//! a command line front end, behind the `cli` feature, which is on by default
+
+use std::{
+    ffi::OsString,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use dustat::{
+    du::{Du, Stats, export, filter::Filter, mt},
+    format::{Bar, HumanSize, Tree},
+};
+
+const USAGE: &str = "\
+Usage: dustat [OPTIONS] [PATH]...
+
+Shows the largest entries of each PATH, or of the current directory.
+
+Options:
+  -d, --depth <N>       levels of the tree to show [default: 1]
+  -n, --top <N>         list the N largest files instead of a tree
+  -x, --exclude <GLOB>  leave out entries matching GLOB, which may be repeated
+  -j, --json            write the whole tree as JSON instead
+      --si              use powers of 1000 rather than 1024
+  -h, --help            show this help
+";
+
+/// the width of the bars showing each entry's share of the total
+const BAR_WIDTH: usize = 20;
+
+struct Args {
+    paths: Vec<PathBuf>,
+    depth: usize,
+    top: Option<usize>,
+    filter: Filter,
+    json: bool,
+    si: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Option<Self>, String> {
+        let mut parsed = Self {
+            paths: Vec::new(),
+            depth: 1,
+            top: None,
+            filter: Filter::new(),
+            json: false,
+            si: false,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| match args.next().map(OsString::into_string) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(value)) => Err(format!("{name} isn't valid UTF-8: {value:?}")),
+                None => Err(format!("{name} needs a value")),
+            };
+            let number = |name: &str, value: String| {
+                (value.parse()).map_err(|_| format!("{name} needs a number, not {value:?}"))
+            };
+
+            match arg.to_str() {
+                Some("-d" | "--depth") => parsed.depth = number("--depth", value("--depth")?)?,
+                Some("-n" | "--top") => parsed.top = Some(number("--top", value("--top")?)?),
+                Some("-x" | "--exclude") => {
+                    parsed.filter = parsed.filter.exclude(&value("--exclude")?);
+                }
+                Some("-j" | "--json") => parsed.json = true,
+                Some("--si") => parsed.si = true,
+                Some("-h" | "--help") => return Ok(None),
+                // everything after `--` is a path, even if it looks like an option
+                Some("--") => parsed.paths.extend(args.by_ref().map(PathBuf::from)),
+                Some(s) if s.starts_with('-') && s != "-" => {
+                    return Err(format!("unknown option {s:?}"));
+                }
+                _ => parsed.paths.push(arg.into()),
+            }
+        }
+
+        if parsed.paths.is_empty() {
+            parsed.paths.push(".".into());
+        }
+        Ok(Some(parsed))
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args_os().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("dustat: {e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut du = Du::new(mt::Source::default());
+    du.path_filter(args.filter);
+    match <[_; 1]>::try_from(args.paths) {
+        Ok([path]) => du.begin(path),
+        Err(paths) => du.begin_many(paths),
+    }
+    du.read(&mut |_, _| true);
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let written = match (args.json, args.top) {
+        (true, _) => export::json(du.stats(), &mut out),
+        (false, Some(n)) => write_top(du.stats(), n, args.si, &mut out),
+        (false, None) => {
+            let tree = Tree::new(du.stats()).depth(args.depth).binary(!args.si);
+            write!(out, "{tree}")
+        }
+    };
+    // a closed pipe, as from `head`, only means nothing more is wanted
+    if let Err(e) = written.and_then(|()| out.flush())
+        && e.kind() != io::ErrorKind::BrokenPipe
+    {
+        eprintln!("dustat: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    for e in du.errors() {
+        eprintln!("dustat: {e}");
+    }
+    match du.errors().is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
+
+/// Writes the `n` largest files, with their paths
+fn write_top(stats: &Stats, n: usize, si: bool, mut out: impl Write) -> io::Result<()> {
+    let total = stats.head().info().size;
+    for (id, info) in stats.top_files(n) {
+        let size = HumanSize::new(info.size).binary(!si);
+        let bar = Bar::new(info.size, total, BAR_WIDTH);
+        writeln!(out, "{size:>10} {bar}  {}", stats.path_of(id).display())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use dustat::du::{FileKind, Info};
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Args>, String> {
+        Args::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn paths_default_to_the_current_directory() {
+        let args = parse(&[]).unwrap().unwrap();
+        assert_eq!(args.paths, [PathBuf::from(".")]);
+        assert_eq!(
+            (args.depth, args.top, args.json, args.si),
+            (1, None, false, false)
+        );
+
+        let args = parse(&["a", "-", "b"]).unwrap().unwrap();
+        assert_eq!(args.paths, [Path::new("a"), Path::new("-"), Path::new("b")]);
+    }
+
+    #[test]
+    fn options_are_read_with_their_values() {
+        let args = parse(&["-d", "3", "--top", "5", "-j", "--si", "-x", "*.log", "p"]);
+        let args = args.unwrap().unwrap();
+        assert_eq!(
+            (args.depth, args.top, args.json, args.si),
+            (3, Some(5), true, true)
+        );
+        assert_eq!(args.paths, [PathBuf::from("p")]);
+
+        let file = |name: &str| Info::new(OsString::from(name), FileKind::File, 0);
+        assert!(!args.filter.test(Path::new("p/a.log"), 0, &file("a.log")));
+        assert!(args.filter.test(Path::new("p/a.txt"), 0, &file("a.txt")));
+
+        assert!(parse(&["-h"]).unwrap().is_none());
+        assert!(parse(&["a", "--help", "--bogus"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn everything_after_a_double_dash_is_a_path() {
+        let args = parse(&["a", "--", "-d", "--", "--json"]).unwrap().unwrap();
+        assert_eq!(
+            args.paths,
+            [
+                Path::new("a"),
+                Path::new("-d"),
+                Path::new("--"),
+                Path::new("--json")
+            ]
+        );
+        assert_eq!((args.depth, args.json), (1, false));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        let err = |args: &[&str]| parse(args).err().unwrap();
+        assert_eq!(err(&["--bogus"]), r#"unknown option "--bogus""#);
+        assert_eq!(err(&["a", "-q"]), r#"unknown option "-q""#);
+        assert_eq!(err(&["--depth"]), "--depth needs a value");
+        assert_eq!(err(&["a", "-x"]), "--exclude needs a value");
+        assert_eq!(
+            err(&["-d", "deep"]),
+            r#"--depth needs a number, not "deep""#
+        );
+        assert_eq!(err(&["-n", "-1"]), r#"--top needs a number, not "-1""#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn values_must_be_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let args = [OsString::from("-x"), OsString::from_vec(b"\xff".to_vec())];
+        let err = Args::parse(args.into_iter()).err().unwrap();
+        assert!(err.starts_with("--exclude isn't valid UTF-8"), "{err}");
+    }
+}
@@ -1,161 +1,1784 @@
 //! disk usage
 
 use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
     error::Error,
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
+    fmt, io,
     num::NonZero,
     ops::{Index, IndexMut},
-    path::PathBuf,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use crate::{fs, util};
+
+pub mod dedupe;
+pub mod diff;
+pub mod export;
+pub mod filter;
 pub mod mt;
+#[cfg(feature = "serde")]
+mod ser;
+mod snapshot;
 pub mod st;
+#[cfg(feature = "async")]
+pub mod tokio;
+pub mod watch;
+
+/// Disk Usage
+#[derive(Debug, Default)]
+pub struct Du<P>(Stats, P, Option<Instant>);
+
+impl<P> Du<P> {
+    pub fn new(provider: P) -> Self {
+        Self(Stats::new(), provider, None)
+    }
+}
+
+impl<P: DuSource> Du<P> {
+    pub fn stats(&self) -> &Stats {
+        &self.0
+    }
+
+    /// The errors hit by the source so far, see [`DuSource::errors`]
+    pub fn errors(&self) -> &[P::Error] {
+        self.1.errors()
+    }
+
+    /// Only count entries, skipping the metadata lookups that provide sizes
+    pub fn dry_run(&mut self, enabled: bool) {
+        self.1.options_mut().dry_run = enabled;
+    }
+
+    /// Choose how symlinks are read
+    pub fn symlinks(&mut self, policy: SymlinkPolicy) {
+        self.1.options_mut().symlinks = policy;
+    }
+
+    /// Count the size of a hardlinked file once, rather than once per link
+    pub fn dedup_hardlinks(&mut self, enabled: bool) {
+        self.1.options_mut().dedup_hardlinks = enabled;
+    }
+
+    /// Stop descending below `max_depth`, where `0` reads only the root's entries
+    ///
+    /// Directories past the limit are still recorded, just not read.
+    pub fn max_depth(&mut self, max_depth: Option<usize>) {
+        self.1.options_mut().max_depth = max_depth;
+    }
+
+    /// Only give nodes to entries down to `depth`, where `0` keeps only the root's entries
+    ///
+    /// Deeper entries are still read, but only add to the totals of their
+    /// deepest ancestor with a node, keeping memory bounded on huge trees.
+    pub fn aggregate_depth(&mut self, depth: Option<usize>) {
+        self.1.options_mut().aggregate_depth = depth;
+    }
+
+    /// Only keep entries for which `filter` returns true
+    ///
+    /// Excluded entries are neither counted nor descended into.
+    pub fn filter(&mut self, filter: impl Fn(&Path, &Info) -> bool + Send + Sync + 'static) {
+        self.1.options_mut().filter = Some(Predicate::new(filter));
+    }
+
+    /// Only keep entries passing `filter`, such as to exclude `/proc` or `node_modules`
+    ///
+    /// Entries are kept only if they pass both this and any [`Du::filter`].
+    pub fn path_filter(&mut self, filter: filter::Filter) {
+        self.1.options_mut().path_filter = Some(filter);
+    }
+
+    /// Sort files into categories by name, see [`Stats::categories`]
+    ///
+    /// Files for which `classify` returns `None` are left uncategorized.
+    pub fn classify(
+        &mut self,
+        classify: impl Fn(&OsStr) -> Option<Category> + Send + Sync + 'static,
+    ) {
+        self.1.options_mut().classifier = Some(Classifier::new(classify));
+    }
+
+    /// Leave out the entries ignored by files named any of `names`, such as `.gitignore`
+    ///
+    /// See [`Options::ignore_files`]. Nothing is left out if `names` is empty.
+    pub fn ignore_files<S: Into<OsString>>(&mut self, names: impl IntoIterator<Item = S>) {
+        self.1.options_mut().ignore_files = names.into_iter().map(Into::into).collect();
+    }
+
+    /// Sort files into categories by their extension, see [`Classifier::by_extension`]
+    pub fn classify_by_extension(&mut self) {
+        self.1.options_mut().classifier = Some(Classifier::by_extension());
+    }
+
+    /// Don't read directories on a different filesystem than the root
+    pub fn one_filesystem(&mut self, enabled: bool) {
+        self.1.options_mut().one_filesystem = enabled;
+    }
+
+    /// Clears the results and the source, reusing their allocations for a rescan
+    ///
+    /// This also undoes a cancellation, see [`Du::cancel_handle`].
+    pub fn reset(&mut self) {
+        self.0.clear();
+        self.1.reset();
+        self.1.options().cancel.reset();
+        self.2 = None;
+    }
+
+    /// A handle which stops the scan from any thread
+    ///
+    /// Once cancelled, reads return promptly and the workers stop, leaving the
+    /// entries read so far. The scan stays cancelled until [`Du::reset`].
+    pub fn cancel_handle(&self) -> CancelToken {
+        self.1.options().cancel.clone()
+    }
+
+    /// Mark entries last modified more than `age` ago as old, see [`Info::old_size`]
+    ///
+    /// The age is measured from when this is called.
+    pub fn older_than(&mut self, age: Option<Duration>) {
+        let cutoff = age.and_then(|age| SystemTime::now().checked_sub(age));
+        self.1.options_mut().older_than = cutoff;
+    }
+
+    /// Record when each entry was last accessed
+    pub fn atime(&mut self, enabled: bool) {
+        self.1.options_mut().atime = enabled;
+    }
+
+    /// Record who owns each entry, see [`Stats::by_owner`]
+    pub fn owners(&mut self, enabled: bool) {
+        self.1.options_mut().owners = enabled;
+    }
+
+    /// Find totals in a single pass once a read finishes, see [`Options::defer_totals`]
+    ///
+    /// This speeds up deep trees, but leaves [`Stats::progress`] and every
+    /// total out of date until then, unless [`Stats::recompute_aggregates`]
+    /// is called sooner.
+    pub fn defer_totals(&mut self, enabled: bool) {
+        self.1.options_mut().defer_totals = enabled;
+    }
+
+    /// Choose which sizes are measured exactly during the scan
+    ///
+    /// Both sizes are always recorded, but the allocated size may fall back to
+    /// the apparent size on platforms where finding it costs extra.
+    pub fn size_mode(&mut self, mode: SizeMode) {
+        self.1.options_mut().size_mode = mode;
+    }
+
+    /// Begins a scan of `path`, which the head then stands for and is named by
+    pub fn begin(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        self.0.set_name(NodeId::ROOT, path.as_os_str());
+        self.1.enqueue(NodeId::ROOT, path, 0);
+        self.1.begin();
+        self.2 = Some(Instant::now());
+    }
+
+    /// Begins a scan of several paths at once, such as multiple mount points
+    ///
+    /// Unlike [`Du::begin`], where the head stands for the path read, each
+    /// path is given a node of its own under the head, named by the path as
+    /// given. Depths are still counted from each path, so their entries are
+    /// at zero.
+    pub fn begin_many(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            let info = util::get_root_info(&path, self.1.options());
+            let id = self.0.push(NodeId::ROOT, info);
+            self.1.enqueue(id, path, 0);
+        }
+        self.1.begin();
+        self.2 = Some(Instant::now());
+    }
+
+    pub fn read_for(&mut self, dur: Duration) -> (usize, Duration) {
+        // TODO: consider reading the elapsed time every n seconds
+        let now = Instant::now();
+        let count = self.read(&mut |_, _| now.elapsed() < dur);
+
+        (count, now.elapsed())
+    }
+
+    /// Reads entries until `with` returns false, passing it the progress so far
+    ///
+    /// Getting the progress is constant time, so this is cheap enough to
+    /// check on every entry. The final call comes after the last entry is
+    /// added, so its entries and bytes are those of the finished read, though
+    /// the source may not yet have counted its last directory as done.
+    pub fn read_with_progress(&mut self, mut with: impl FnMut(Progress) -> bool) -> usize {
+        let began = self.2;
+        self.read(&mut |stats, source| with(progress(stats, source, began)))
+    }
+
+    /// The progress of the scan so far, timed from the last call to [`Du::begin`] or [`Du::begin_many`]
+    pub fn progress(&self) -> Progress {
+        progress(&self.0, &self.1, self.2)
+    }
+
+    pub fn read(&mut self, with: &mut impl FnMut(&mut Stats, &mut P) -> bool) -> usize {
+        let cancel = self.1.options().cancel.clone();
+        let mut count = 0;
+        // checked before taking an entry, so a stop never drops one
+        while !cancel.is_cancelled() && with(&mut self.0, &mut self.1) {
+            let entry = match self.1.wait_entry(WAIT) {
+                NextEntry::Ready(entry) => entry,
+                // checked again between waits, so a stop needn't wait for an entry
+                NextEntry::Pending => continue,
+                NextEntry::Finished => {
+                    self.finish_totals();
+                    break;
+                }
+            };
+            self.add(entry);
+            count += 1;
+        }
+
+        if cancel.is_cancelled() {
+            self.1.finish();
+        }
+        count
+    }
+
+    /// Finds the totals left out of date by [`Options::defer_totals`]
+    fn finish_totals(&mut self) {
+        if self.0.stale {
+            self.0.recompute_aggregates();
+        }
+    }
+
+    /// Deletes the entry of `id` from disk, then removes it from the stats
+    ///
+    /// Returns the space freed, as counted by the scan, so hardlinks with
+    /// other links left don't actually free their data. If deleting fails,
+    /// part of a directory may still have been deleted, but the stats are left
+    /// as they were, so a rescan is needed to find what remains.
+    ///
+    /// Must only be called between scans, as entries still to be read may
+    /// refer to nodes which are renumbered, see [`Stats::remove`]. The head
+    /// can't be removed, being the path scanned.
+    pub fn remove(&mut self, id: NodeId) -> Result<Usage, ScanError> {
+        let path = self.0.path_of(id);
+        let parent = self.0[id].parent;
+        let error = |op, e| ScanError::new(&path, op, parent, e);
+        if id == NodeId::ROOT {
+            return Err(error(ScanOp::Remove, io::ErrorKind::InvalidInput.into()));
+        }
+
+        let removed = match self.0.kind(id) {
+            FileKind::Dir => std::fs::remove_dir_all(&path),
+            _ => std::fs::remove_file(&path),
+        };
+        removed.map_err(|e| error(ScanOp::Remove, e))?;
+        Ok(self.0.remove(id))
+    }
+
+    /// The space [`Du::remove`] would free, without deleting anything
+    pub fn remove_dry_run(&self, id: NodeId) -> Usage {
+        Usage::total(&self.0[id].info)
+    }
+
+    /// Reads the directory of `id` again, replacing everything found within it
+    ///
+    /// The totals of its ancestors are patched as the new entries are read,
+    /// so only the directory itself is scanned. Rescanning the head of
+    /// [`Du::begin_many`] rescans each of its paths. Entries which aren't
+    /// directories are left as they are.
+    ///
+    /// Like [`Du::remove`], this renumbers the nodes after `id`, so must only
+    /// be called between scans. Directories reached through symlinks are
+    /// still seen as visited, so they're kept as links when read again.
+    pub fn rescan(&mut self, id: NodeId) {
+        match id == NodeId::ROOT && self.has_roots() {
+            true => {
+                for i in 0..self.0.head().children.len() {
+                    self.requeue(self.0.head().children[i], 0);
+                }
+            }
+            false => self.requeue(id, self.depth_of(id)),
+        }
+        self.1.begin();
+    }
+
+    /// The filesystem each root of the scan is on, along with how much of it the root takes
+    ///
+    /// Roots are the path given to [`Du::begin`], or each of those given to
+    /// [`Du::begin_many`]. Their sizes are only complete once the scan is.
+    pub fn volumes(&self) -> Vec<Result<fs::Volume, ScanError>> {
+        let roots = match self.has_roots() {
+            true => self.0.head().children.as_slice(),
+            false => &[NodeId::ROOT],
+        };
+        (roots.iter().copied())
+            .map(|root| {
+                let path = self.0.path_of(root);
+                let space =
+                    fs::space(&path).map_err(|e| ScanError::new(&path, ScanOp::Volume, root, e))?;
+                Ok(fs::Volume {
+                    root,
+                    space,
+                    alloc: self.0[root].info.alloc,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether the scan began with [`Du::begin_many`], whose head is unnamed
+    fn has_roots(&self) -> bool {
+        self.0.name(NodeId::ROOT).is_empty()
+    }
+
+    /// The depth of the entries read from the directory of `id`
+    fn depth_of(&self, id: NodeId) -> usize {
+        let mut depth: usize = 0;
+        let mut p = id;
+        while p != self.0[p].parent {
+            depth += 1;
+            p = self.0[p].parent;
+        }
+        // the paths of `begin_many` are read at zero, a level below the head
+        depth.saturating_sub(self.has_roots() as usize)
+    }
+
+    /// The info of `id` read again from `path`
+    fn restat(&self, id: NodeId, path: &Path) -> Info {
+        // the head of `begin` stands for its path, without any info of its own
+        match id == NodeId::ROOT {
+            true => Info::default(),
+            false => util::get_root_info(path, self.1.options()),
+        }
+    }
+
+    /// Drops the contents of `id`, then queues it to be read with its entries at `depth`
+    fn requeue(&mut self, id: NodeId, depth: usize) {
+        if id != NodeId::ROOT && !self.0.is_dir(id) {
+            return;
+        }
+
+        let links: Vec<_> = (self.0.dfs(id).skip(1))
+            .filter_map(|(_, node)| node.info.inode.filter(|_| !node.info.duplicate))
+            .filter(|inode| inode.nlink > 1)
+            .collect();
+        self.1.forget_links(&links);
+
+        let path = self.0.path_of(id);
+        let info = self.restat(id, &path);
+        self.0.replace(id, info);
+
+        // an entry at the deepest depth read would never have been read either
+        if self.1.options().max_depth.is_none_or(|max| depth <= max) {
+            self.1.enqueue(id, path, depth);
+        }
+    }
+
+    /// Adds an entry to the stats, then queues it to be read if it should be
+    fn add(&mut self, entry: Entry) {
+        let Self(stats, provider, _) = self;
+        let options = provider.options();
+
+        let descend = entry.descend && options.max_depth.is_none_or(|max| entry.depth < max);
+        let keep = options.aggregate_depth.is_none_or(|max| entry.depth <= max);
+        // otherwise every total is kept up to date as the entry is added
+        stats.stale |= options.defer_totals;
+        let next = match (keep, options.defer_totals) {
+            (true, false) => stats.push(entry.parent, entry.info),
+            (true, true) => stats.push_deferred(entry.parent, entry.info),
+            // its own entries are then folded into the same ancestor
+            (false, false) => stats.fold(entry.parent, &entry.info),
+            (false, true) => {
+                stats.fold_deferred(entry.parent, &entry.info);
+                entry.parent
+            }
+        };
+        if descend {
+            provider.enqueue(next, entry.path, entry.depth + 1);
+        }
+    }
+}
+
+pub trait DuSource {
+    type Error: Error;
+
+    fn begin(&mut self);
+    fn finish(&mut self);
+    /// Stops any scan and clears all state, ready to begin a new one
+    fn reset(&mut self);
+
+    /// Takes the next entry, waiting for one if the scan is still going
+    ///
+    /// Returns `None` only once the scan is finished.
+    fn next_entry(&mut self) -> Option<Entry>;
+    /// Takes the next entry if one is ready, without waiting for it
+    ///
+    /// Sources which never wait may rely on the default, which defers to
+    /// [`DuSource::next_entry`].
+    fn poll_entry(&mut self) -> NextEntry {
+        match self.next_entry() {
+            Some(entry) => NextEntry::Ready(entry),
+            None => NextEntry::Finished,
+        }
+    }
+    /// Takes the next entry, waiting at most `timeout` for one to be ready
+    ///
+    /// Sources which wait for their entries should sleep while doing so,
+    /// rather than spin. The default only polls.
+    fn wait_entry(&mut self, timeout: Duration) -> NextEntry {
+        let _ = timeout;
+        self.poll_entry()
+    }
+    /// Queues a directory to be read, its entries being at `depth`
+    fn enqueue(&mut self, parent: NodeId, path: PathBuf, depth: usize);
+
+    fn errors(&self) -> &[Self::Error];
+    fn counters(&self) -> &Counters;
+    fn options(&self) -> &Options;
+    fn options_mut(&mut self) -> &mut Options;
+
+    /// A best-effort estimate of the entries left to be produced
+    ///
+    /// The estimate may be far off for unevenly shaped trees, and may
+    /// change drastically as the scan progresses.
+    fn estimated_remaining(&self) -> Option<usize> {
+        None
+    }
+
+    /// The directories found but not yet read, if known
+    fn pending_dirs(&self) -> Option<usize> {
+        None
+    }
+
+    /// Forgets that these hardlinks were seen, so they're counted when next read
+    ///
+    /// Used by [`Du::rescan`], so the files it reads again aren't taken to
+    /// be repeats of themselves. Sources which don't dedupe needn't do anything.
+    fn forget_links(&mut self, links: &[Inode]) {
+        let _ = links;
+    }
+
+    /// Reads the entries of the directory at `path` right away, rather than queueing it
+    ///
+    /// Used by [`Du::update`] to compare a directory with its nodes, where
+    /// errors are kept with the rest. Sources which can't return `None`, so
+    /// the directory is rescanned instead.
+    fn read_dir_now(&mut self, parent: NodeId, path: &Path, depth: usize) -> Option<Vec<Entry>> {
+        let _ = (parent, path, depth);
+        None
+    }
+}
+
+/// The longest a read waits for an entry before checking whether to stop
+pub(crate) const WAIT: Duration = Duration::from_millis(10);
+
+/// The outcome of polling a [`DuSource`] for its next entry
+// taken by value like an `Option<Entry>`, which boxing would only slow down
+#[allow(clippy::large_enum_variant)]
+pub enum NextEntry {
+    Ready(Entry),
+    /// no entry is ready, but the scan is still going
+    Pending,
+    /// every entry has been taken
+    Finished,
+}
+
+/// A node ordered from largest to smallest, for [`Stats::largest`]
+///
+/// Ties are broken by name, then by the node itself so the order is total.
+#[derive(PartialEq, Eq)]
+struct Ranked<'a> {
+    size: u64,
+    name: &'a OsStr,
+    id: NodeId,
+}
+
+impl Ord for Ranked<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.size.cmp(&self.size))
+            .then_with(|| self.name.cmp(other.name))
+            .then_with(|| self.id.get().cmp(&other.id.get()))
+    }
+}
+
+impl PartialOrd for Ranked<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Settings shared by every [`DuSource`], applied when reading directories
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// read entries' names and kinds only, leaving sizes at zero
+    pub dry_run: bool,
+    pub symlinks: SymlinkPolicy,
+    /// count the size of a hardlinked file only for the first link found
+    pub dedup_hardlinks: bool,
+    /// the deepest entries whose directories are read, unlimited if `None`
+    pub max_depth: Option<usize>,
+    /// the deepest entries given a node, the rest only adding to their
+    /// ancestors' totals, unlimited if `None`
+    pub aggregate_depth: Option<usize>,
+    /// decides which entries are kept, all of them if `None`
+    pub filter: Option<Predicate>,
+    /// the patterns deciding which entries are kept, applied along with any `filter`
+    pub path_filter: Option<filter::Filter>,
+    /// which sizes need to be found exactly, where it costs extra to find them
+    pub size_mode: SizeMode,
+    /// don't read directories on a different device than the root, like `du -x`
+    ///
+    /// Devices are the device number on Unix, known only from metadata, so
+    /// this does nothing there in a dry run. On Windows, volume serial numbers
+    /// are used instead, and other platforms don't report devices at all.
+    pub one_filesystem: bool,
+    /// stops the scan once cancelled, see [`Du::cancel_handle`]
+    pub cancel: CancelToken,
+    /// entries last modified before this are marked as old, none if `None`
+    pub older_than: Option<SystemTime>,
+    /// record access times, which some platforms only find with extra work
+    pub atime: bool,
+    /// record the owner of each entry, which is only known on Unix
+    pub owners: bool,
+    /// sorts files into categories, none if `None`
+    pub classifier: Option<Classifier>,
+    /// leave totals to be found once a read finishes, rather than as each entry is added
+    ///
+    /// Adding an entry then takes constant time, rather than time in its
+    /// depth, but totals are out of date until the read finishes.
+    pub defer_totals: bool,
+    /// the names of the files whose rules leave out entries, like `.gitignore`
+    ///
+    /// Each directory's files are read before its entries, with their rules
+    /// applying to everything within it, following git's semantics. Files
+    /// above the roots of a scan are never read.
+    pub ignore_files: Vec<OsString>,
+}
+
+/// How symlinks are read during a scan
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    /// recorded as a [`FileKind::Symlink`] with the size of the link itself
+    #[default]
+    Record,
+    /// left out of the scan entirely
+    Skip,
+    /// read as their targets, except those leading back into the scan
+    ///
+    /// Links to directories already seen stay as a [`FileKind::Symlink`].
+    Follow,
+}
+
+/// A check run against each entry's path and info as it's read
+///
+/// Runs on the worker threads of a [`mt::Source`], so it must be shareable.
+#[derive(Clone)]
+pub struct Predicate(Arc<PredicateFn>);
+
+type PredicateFn = dyn Fn(&Path, &Info) -> bool + Send + Sync;
+
+impl Predicate {
+    pub fn new(f: impl Fn(&Path, &Info) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn test(&self, path: &Path, info: &Info) -> bool {
+        (self.0)(path, info)
+    }
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Predicate").finish_non_exhaustive()
+    }
+}
+
+/// Sorts files into categories by their names, see [`Du::classify`]
+///
+/// Like a [`Predicate`], it must be shareable.
+#[derive(Clone)]
+pub struct Classifier(Arc<ClassifierFn>);
+
+type ClassifierFn = dyn Fn(&OsStr) -> Option<Category> + Send + Sync;
+
+impl Classifier {
+    pub fn new(f: impl Fn(&OsStr) -> Option<Category> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Categorizes files by their lowercased extension, so `a.MP4` is in `mp4`
+    ///
+    /// Files without an extension, including dotfiles, are left uncategorized.
+    pub fn by_extension() -> Self {
+        Self::new(|name| {
+            let ext = Path::new(name).extension()?;
+            Some(Category::new(ext.to_string_lossy().to_lowercase()))
+        })
+    }
+
+    pub fn classify(&self, name: &OsStr) -> Option<Category> {
+        (self.0)(name)
+    }
+}
+
+impl fmt::Debug for Classifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Classifier").finish_non_exhaustive()
+    }
+}
+
+/// A named category of files, such as `mp4` or `video`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Category(Cow<'static, str>);
+
+impl Category {
+    pub fn new(name: impl Into<Cow<'static, str>>) -> Self {
+        Self(name.into())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A flag which stops a scan, shared between its clones
+///
+/// Sources check it between entries, so they stop promptly even in the middle
+/// of a large directory.
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.store(false, Relaxed);
+    }
+}
+
+/// The IO work performed by a [`DuSource`], as opposed to the disk usage it reports
+#[derive(Debug, Default)]
+pub struct Counters {
+    dirs_read: AtomicUsize,
+    stat_calls: AtomicUsize,
+    entries_read: AtomicUsize,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// directories opened for reading, includes failed attempts
+    pub fn dirs_read(&self) -> usize {
+        self.dirs_read.load(Relaxed)
+    }
+
+    /// metadata lookups performed on entries
+    pub fn stat_calls(&self) -> usize {
+        self.stat_calls.load(Relaxed)
+    }
+
+    /// entries produced from the directories read
+    pub fn entries_read(&self) -> usize {
+        self.entries_read.load(Relaxed)
+    }
+
+    /// the average number of entries per directory read so far
+    pub fn fan_out(&self) -> f64 {
+        self.entries_read() as f64 / self.dirs_read().max(1) as f64
+    }
+
+    /// estimates the entries left given the directories still to be read
+    pub(crate) fn estimate(&self, pending_dirs: usize) -> usize {
+        (pending_dirs as f64 * self.fan_out()).ceil() as usize
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub(crate) fn add_dir_read(&self) {
+        self.dirs_read.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn add_stat_call(&self) {
+        self.stat_calls.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn add_entry_read(&self) {
+        self.entries_read.fetch_add(1, Relaxed);
+    }
+}
+
+/// An error hit while scanning, along with where and how it was hit
+#[derive(Debug)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub op: ScanOp,
+    /// the directory left partially scanned, whose entries are missing some
+    pub node: NodeId,
+    pub source: io::Error,
+}
+
+impl ScanError {
+    pub fn new(path: impl Into<PathBuf>, op: ScanOp, node: NodeId, source: io::Error) -> Self {
+        Self {
+            path: path.into(),
+            op,
+            node,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.path.display(), self.op, self.source)
+    }
+}
+
+impl Error for ScanError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// What was being done when a [`ScanError`] was hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanOp {
+    /// opening a directory, so none of its entries were read
+    ReadDir,
+    /// reading the next entry of a directory, so the rest of it was lost
+    NextEntry,
+    /// reading the metadata of an entry, so only that entry was lost
+    Metadata,
+    /// deleting an entry, see [`Du::remove`]
+    Remove,
+    /// watching a directory for changes, see [`Du::watch`]
+    Watch,
+    /// finding the space of a filesystem, see [`Du::volumes`]
+    Volume,
+    /// hashing the contents of a file, see [`dedupe::Finder`]
+    Hash,
+}
+
+impl fmt::Display for ScanOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ReadDir => "failed to read directory",
+            Self::NextEntry => "failed to read directory entry",
+            Self::Metadata => "failed to read metadata",
+            Self::Remove => "failed to remove",
+            Self::Watch => "failed to watch directory",
+            Self::Volume => "failed to read filesystem space",
+            Self::Hash => "failed to hash file",
+        })
+    }
+}
+
+pub struct Entry {
+    parent: NodeId,
+    info: Info,
+    path: PathBuf,
+    /// the distance from the scan's root, whose own entries are at zero
+    depth: usize,
+    /// whether the entry is a directory which should be read
+    descend: bool,
+}
+
+impl Entry {
+    pub fn new(parent: NodeId, info: Info, path: PathBuf, depth: usize) -> Self {
+        Self {
+            parent,
+            descend: info.kind == FileKind::Dir,
+            info,
+            path,
+            depth,
+        }
+    }
+
+    /// keeps the entry from being read, even if it's a directory
+    pub(crate) fn without_descent(mut self) -> Self {
+        self.descend = false;
+        self
+    }
+}
+
+/// The tree of entries read by a scan
+///
+/// Names are kept apart from each node's [`Info`], packed together so each
+/// doesn't need an allocation of its own, see [`Stats::name`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ser::RawStats"))]
+pub struct Stats {
+    nodes: Vec<Node>,
+    /// the encoded names of every node, only ever appended to until cleared
+    names: Vec<u8>,
+    /// whether nodes were added without their totals, see [`Options::defer_totals`]
+    stale: bool,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        let nodes = vec![Node::new(Info::default(), NodeId::ROOT)];
+        Self {
+            nodes,
+            names: Vec::new(),
+            stale: false,
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes every node but the head, keeping the allocations
+    pub fn clear(&mut self) {
+        self.nodes.truncate(1);
+        self.names.clear();
+        self.stale = false;
+        let head = &mut self.nodes[0];
+        head.info = Info::default();
+        head.name = Name::default();
+        head.children.clear();
+        head.folded = None;
+        head.categories = None;
+    }
+
+    /// The entries and bytes added so far, see [`Du::progress`] for the rest
+    pub fn progress(&self) -> Progress {
+        Progress {
+            entries: self.nodes.len() - 1,
+            bytes: self.head().info.size,
+            ..Progress::default()
+        }
+    }
+
+    /// The name of `id`, where the head is named by the path a scan began at
+    pub fn name(&self, id: NodeId) -> &OsStr {
+        self[id].name.get(&self.names)
+    }
+
+    /// Renames `id`, leaving the bytes of its old name unused
+    fn set_name(&mut self, id: NodeId, name: &OsStr) {
+        self[id].name = self.intern(name);
+    }
+
+    fn intern(&mut self, name: &OsStr) -> Name {
+        let start = self.names.len();
+        self.names.extend_from_slice(name.as_encoded_bytes());
+        Name {
+            start,
+            len: name.len(),
+        }
+    }
+
+    /// Replaces the info of `id`, keeping its name
+    fn set_info(&mut self, id: NodeId, mut info: Info) {
+        info.name = Box::default();
+        self[id].info = info;
+    }
+
+    pub fn head(&self) -> &Node {
+        &self[NodeId::ROOT]
+    }
+
+    pub fn parent(&self, id: NodeId) -> &Node {
+        &self[self[id].parent]
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self[id].children
+    }
+
+    /// The path of `id`, joining the names of its ancestors from the head
+    ///
+    /// The head is named by the path a scan began at, so this is the path
+    /// the entry was read from.
+    pub fn path_of(&self, id: NodeId) -> PathBuf {
+        let mut names = Vec::new();
+        let mut p = id;
+        while p != self[p].parent {
+            names.push(self.name(p));
+            p = self[p].parent;
+        }
+        names.push(self.name(p));
+
+        names.into_iter().rev().collect()
+    }
+
+    /// The node read from `path`, written as it would be by [`Stats::path_of`]
+    ///
+    /// Paths are compared by their components, but never resolved, so a
+    /// relative path only matches a scan which began at a relative path.
+    pub fn find(&self, path: &Path) -> Option<NodeId> {
+        let mut rest = path.strip_prefix(self.name(NodeId::ROOT)).ok()?;
+        let mut id = NodeId::ROOT;
+        // the names of nodes given by `Du::begin_many` may span components
+        while rest.components().next().is_some() {
+            (id, rest) = (self[id].children.iter()).find_map(|&c| {
+                let rest = rest.strip_prefix(self.name(c)).ok()?;
+                Some((c, rest))
+            })?;
+        }
+
+        Some(id)
+    }
+
+    /// Visits the subtree of `from` depth-first, starting with `from`
+    pub fn dfs(&self, from: NodeId) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.walk(from).map(|(_, id, node)| (id, node))
+    }
+
+    /// Visits the subtree of `from` breadth-first, starting with `from`
+    pub fn bfs(&self, from: NodeId) -> impl Iterator<Item = (NodeId, &Node)> {
+        let mut queue = VecDeque::from([from]);
+        std::iter::from_fn(move || {
+            let id = queue.pop_front()?;
+            let node = &self[id];
+            queue.extend(&node.children);
+            Some((id, node))
+        })
+    }
+
+    /// Walks the subtree of `from` depth-first, along with each node's depth
+    ///
+    /// `from` itself is yielded first, at a depth of zero.
+    pub fn walk(&self, from: NodeId) -> impl Iterator<Item = (usize, NodeId, &Node)> {
+        let mut stack = vec![(0, from)];
+        std::iter::from_fn(move || {
+            let (depth, id) = stack.pop()?;
+            let node = &self[id];
+            stack.extend(node.children.iter().rev().map(|&c| (depth + 1, c)));
+            Some((depth, id, node))
+        })
+    }
+
+    /// Attaches data of the caller's own to every node, see [`NodeMap`]
+    ///
+    /// Nodes are visited in the order they were added, so each parent is
+    /// mapped before its children.
+    pub fn map_nodes<T>(&self, f: impl FnMut(NodeId, &Node) -> T) -> NodeMap<T> {
+        let mut map = NodeMap::new();
+        map.extend(self, f);
+        map
+    }
+
+    pub fn size(&self, id: NodeId, mode: SizeMode) -> u64 {
+        self[id].info.size_in(mode)
+    }
+
+    pub fn kind(&self, id: NodeId) -> FileKind {
+        self[id].info.kind
+    }
+
+    pub fn is_dir(&self, id: NodeId) -> bool {
+        self.kind(id) == FileKind::Dir
+    }
+
+    pub fn is_file(&self, id: NodeId) -> bool {
+        self.kind(id) == FileKind::File
+    }
+
+    /// Sorts the children of `id` with the given comparator
+    pub fn sort_children_by(&mut self, id: NodeId, cmp: impl Fn(&Node, &Node) -> Ordering) {
+        let mut children = std::mem::take(&mut self[id].children);
+        children.sort_by(|&a, &b| cmp(&self[a], &self[b]));
+        self[id].children = children;
+    }
+
+    /// Sorts the children of `id` from largest to smallest
+    pub fn sort_children_by_size(&mut self, id: NodeId) {
+        self.sort_children_by(id, |a, b| b.info.size.cmp(&a.info.size));
+    }
+
+    /// Sorts the children of `id` by name
+    pub fn sort_children_by_name(&mut self, id: NodeId) {
+        let names = std::mem::take(&mut self.names);
+        self.sort_children_by(id, |a, b| a.name.get(&names).cmp(b.name.get(&names)));
+        self.names = names;
+    }
+
+    /// The `n` largest children of `id`, from largest to smallest
+    ///
+    /// Ties are broken by name, so the order is stable across scans.
+    pub fn largest_children(&self, id: NodeId, n: usize) -> Vec<NodeId> {
+        self.largest(self[id].children.iter().copied(), n)
+    }
+
+    /// The `n` largest nodes under `id`, from largest to smallest
+    ///
+    /// Ties are broken by name, so the order is stable across scans.
+    pub fn largest_descendants(&self, id: NodeId, n: usize) -> Vec<NodeId> {
+        self.largest(self.walk(id).skip(1).map(|(_, id, _)| id), n)
+    }
+
+    /// The `n` largest directories, from largest to smallest
+    ///
+    /// Sizes include contents, so ancestors of large directories show up as
+    /// well. Only the `n` largest seen so far are kept, rather than sorting
+    /// every node.
+    pub fn top_dirs(&self, n: usize) -> Vec<(NodeId, &Info)> {
+        self.top(n, FileKind::Dir)
+    }
+
+    /// The `n` largest files, from largest to smallest
+    pub fn top_files(&self, n: usize) -> Vec<(NodeId, &Info)> {
+        self.top(n, FileKind::File)
+    }
+
+    fn top(&self, n: usize, kind: FileKind) -> Vec<(NodeId, &Info)> {
+        let ids = (1..self.nodes.len())
+            .map(NodeId::new)
+            .filter(|&id| self.kind(id) == kind);
+        let ids = self.largest(ids, n);
+        ids.into_iter().map(|id| (id, &self[id].info)).collect()
+    }
+
+    /// The `n` largest of `ids`, taking time in `ids.len() * log(n)`
+    fn largest(&self, ids: impl IntoIterator<Item = NodeId>, n: usize) -> Vec<NodeId> {
+        if n == 0 {
+            return Vec::new();
+        }
+        // the smallest of those kept is on top, to be dropped by a larger one
+        let mut heap = BinaryHeap::with_capacity(n + 1);
+        for id in ids {
+            heap.push(Ranked {
+                size: self[id].info.size,
+                name: self.name(id),
+                id,
+            });
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+        (heap.into_sorted_vec().into_iter())
+            .map(|ranked| ranked.id)
+            .collect()
+    }
+
+    /// The directories not modified since `cutoff`, from largest to smallest
+    ///
+    /// Only the topmost of these are listed, as everything within them is at
+    /// least as old. Directories without a known modification time are never
+    /// listed, even if empty.
+    pub fn untouched_since(&self, cutoff: SystemTime) -> Vec<NodeId> {
+        let mut dirs = Vec::new();
+        let mut stack = self.head().children.clone();
+        while let Some(id) = stack.pop() {
+            let info = &self[id].info;
+            if info.kind != FileKind::Dir {
+                continue;
+            }
+            match info.newest.is_some_and(|newest| newest < cutoff) {
+                true => dirs.push(id),
+                false => stack.extend(&self[id].children),
+            }
+        }
+        self.largest(dirs, usize::MAX)
+    }
+
+    /// The space used under `id` by each user, including `id` itself
+    ///
+    /// Each entry counts its own size towards its owner, so owners are only
+    /// known if recorded with [`Du::owners`]. Entries without a known owner,
+    /// or without a node of their own, aren't counted.
+    pub fn by_owner(&self, id: NodeId) -> HashMap<u32, Usage> {
+        self.usage_by(id, |owner| owner.uid)
+    }
+
+    /// The space used under `id` by each group, like [`Stats::by_owner`]
+    pub fn by_group(&self, id: NodeId) -> HashMap<u32, Usage> {
+        self.usage_by(id, |owner| owner.gid)
+    }
+
+    fn usage_by(&self, id: NodeId, key: impl Fn(Owner) -> u32) -> HashMap<u32, Usage> {
+        let mut usage: HashMap<_, Usage> = HashMap::new();
+        for (_, node) in self.dfs(id) {
+            let info = &node.info;
+            if let Some(owner) = info.owner {
+                usage.entry(key(owner)).or_default().add(Usage::of(info));
+            }
+        }
+        usage
+    }
+
+    /// The space used under `id` by each category of file, from largest to smallest
+    ///
+    /// Files are only categorized if classified with [`Du::classify`]. Unlike
+    /// [`Stats::by_owner`], the totals are kept as the scan goes, so they
+    /// include entries folded by [`Du::aggregate_depth`].
+    pub fn categories(&self, id: NodeId) -> Vec<(&Category, Usage)> {
+        let node = &self[id];
+        let mut categories: Vec<_> = match (&node.categories, &node.info.category) {
+            (Some(categories), _) => categories.total.iter().map(|(c, u)| (c, *u)).collect(),
+            (None, Some(category)) => vec![(category, Usage::of(&node.info))],
+            (None, None) => Vec::new(),
+        };
+        categories.sort_by(|a, b| b.1.size.cmp(&a.1.size).then_with(|| a.0.cmp(b.0)));
+        categories
+    }
+
+    /// Groups the top-level directories into bins of at most `bin_size` bytes
+    ///
+    /// Uses a first-fit-decreasing heuristic over recursive sizes, so the
+    /// result is not guaranteed to use the fewest possible bins.
+    pub fn bin_pack(&self, bin_size: u64) -> Bins {
+        let mut dirs: Vec<_> = (self.head().children.iter().copied())
+            .filter(|&id| self.is_dir(id))
+            .collect();
+        dirs.sort_by(|&a, &b| self[b].info.size.cmp(&self[a].info.size));
+
+        let mut packing = Bins::default();
+        let mut free = Vec::new();
+        for id in dirs {
+            let size = self[id].info.size;
+            if size > bin_size {
+                packing.oversize.push(id);
+                continue;
+            }
+
+            match free.iter().position(|&f| f >= size) {
+                Some(i) => {
+                    free[i] -= size;
+                    packing.bins[i].push(id);
+                }
+                None => {
+                    free.push(bin_size - size);
+                    packing.bins.push(vec![id]);
+                }
+            }
+        }
+
+        packing
+    }
+
+    /// Groups files which share the same storage, i.e. hardlinks
+    ///
+    /// Only files with more than one link are considered, and only groups
+    /// with more than one member are returned.
+    pub fn hardlink_groups(&self) -> Vec<Vec<NodeId>> {
+        let mut groups: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(inode) = node.info.inode
+                && inode.nlink > 1
+                && node.info.kind == FileKind::File
+            {
+                groups
+                    .entry((inode.dev, inode.ino))
+                    .or_default()
+                    .push(NodeId::new(i));
+            }
+        }
+
+        let mut groups: Vec<_> = groups.into_values().filter(|g| g.len() > 1).collect();
+        groups.sort_by_key(|g| g[0].get());
+        groups
+    }
+
+    /// The most directories each one is compared against in [`Stats::similar_dirs`]
+    pub const SIMILAR_CANDIDATES: usize = 256;
+
+    /// Finds the `limit` pairs of directories with the most similar contents
+    ///
+    /// Directories are compared by their children's names and sizes, with a
+    /// similarity of `1.0` meaning identical signatures. Only pairs at or above
+    /// `threshold` are returned, from most to least similar. Since directories
+    /// of very different sizes can't reach a high similarity, each is only
+    /// compared against the next [`Stats::SIMILAR_CANDIDATES`] of comparable
+    /// size, so a higher threshold makes this cheaper.
+    ///
+    /// Directories smaller than `min_size` are left out, as are pairs where
+    /// one holds the other, which are alike by containment alone.
+    pub fn similar_dirs(
+        &self,
+        threshold: f64,
+        min_size: u64,
+        limit: usize,
+    ) -> Vec<(NodeId, NodeId, f64)> {
+        // an empty file still counts towards the signature
+        let weight = |id: NodeId| self[id].info.size.saturating_add(1);
+
+        let mut dirs: Vec<_> = (self.nodes.iter().enumerate())
+            .filter(|(_, node)| node.info.kind == FileKind::Dir && !node.children.is_empty())
+            .filter(|(_, node)| node.info.size >= min_size)
+            .map(|(i, node)| {
+                let sig: HashMap<&OsStr, u64> = (node.children.iter())
+                    .map(|&c| (self.name(c), weight(c)))
+                    .collect();
+                (sig.values().sum::<u64>(), NodeId::new(i), sig)
+            })
+            .collect();
+        dirs.sort_by_key(|&(total, id, _)| (total, id.get()));
+
+        let mut pairs = Vec::new();
+        for (i, (a_total, a, a_sig)) in dirs.iter().enumerate() {
+            for (b_total, b, b_sig) in dirs[i + 1..].iter().take(Self::SIMILAR_CANDIDATES) {
+                // the similarity can be at most the ratio of the totals
+                if (*a_total as f64) < threshold * *b_total as f64 {
+                    break;
+                }
+                if self.is_within(*a, *b) || self.is_within(*b, *a) {
+                    continue;
+                }
+
+                let shared: u64 = (a_sig.iter())
+                    .filter_map(|(name, &a)| Some(a.min(*b_sig.get(name)?)))
+                    .sum();
+                let similarity = shared as f64 / (a_total + b_total - shared) as f64;
+                if similarity >= threshold {
+                    pairs.push((*a, *b, similarity));
+                }
+            }
+        }
+
+        pairs.sort_by(|(a1, b1, x), (a2, b2, y)| {
+            (y.total_cmp(x)).then_with(|| (a1.get(), b1.get()).cmp(&(a2.get(), b2.get())))
+        });
+        pairs.truncate(limit);
+        pairs
+    }
+
+    /// Whether `id` is somewhere below `dir`
+    fn is_within(&self, id: NodeId, dir: NodeId) -> bool {
+        let mut p = id;
+        while p != self[p].parent {
+            p = self[p].parent;
+            if p == dir {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Lists the visible rows of a collapsible tree, along with their depth
+    ///
+    /// Rows start at the children of the head, and the children of a node are
+    /// only included when it's within `expanded`.
+    pub fn flat_view(&self, expanded: &HashSet<NodeId>) -> Vec<(NodeId, usize)> {
+        let mut rows = Vec::new();
+        let head = &self.head().children;
+        let mut stack: Vec<_> = head.iter().rev().map(|&id| (id, 0)).collect();
+        while let Some((id, depth)) = stack.pop() {
+            rows.push((id, depth));
+            if expanded.contains(&id) {
+                stack.extend(self[id].children.iter().rev().map(|&c| (c, depth + 1)));
+            }
+        }
+
+        rows
+    }
+
+    /// Visits every node, allowing its [`Info`] to be edited in place
+    ///
+    /// Totals aren't kept up to date with the edits, so sizes should be
+    /// changed through [`Info::own`] and [`Info::own_alloc`] followed by a
+    /// call to [`Stats::recompute_aggregates`].
+    pub fn for_each_info_mut(&mut self, mut f: impl FnMut(NodeId, &mut Info)) {
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            f(NodeId::new(i), &mut node.info);
+        }
+    }
+
+    /// Rebuilds every node's totals from the own values of its subtree
+    pub fn recompute_aggregates(&mut self) {
+        self.stale = false;
+        for node in &mut self.nodes {
+            node.reset();
+        }
+
+        // children are always pushed after their parent
+        for i in (1..self.nodes.len()).rev() {
+            self.absorb(self.nodes[i].parent, NodeId::new(i));
+        }
+    }
 
-/// Disk Usage
-#[derive(Debug, Default)]
-pub struct Du<P>(Stats, P);
+    /// Removes `id` and everything within it from the stats, returning their usage
+    ///
+    /// This only changes the stats, see [`Du::remove`] to also delete the
+    /// entry. Nodes are kept in order, so those after `id` are renumbered,
+    /// leaving any ids taken before this pointing elsewhere. Ids which come
+    /// before `id`, among them its ancestors, stay the same.
+    ///
+    /// # Panics
+    ///
+    /// If `id` is the head.
+    pub fn remove(&mut self, id: NodeId) -> Usage {
+        assert_ne!(id, NodeId::ROOT, "the head can't be removed");
+        let usage = Usage::total(&self[id].info);
+        let parent = self[id].parent;
+        self.prune(&[id]);
+        // ancestors precede their descendants, so `parent` is still the same
+        self.refresh(parent);
+        usage
+    }
 
-impl<P> Du<P> {
-    pub fn new(provider: P) -> Self {
-        Self(Stats::new(), provider)
+    /// Replaces the info of `id`, dropping everything within it
+    fn replace(&mut self, id: NodeId, info: Info) {
+        let children = self[id].children.clone();
+        self.prune(&children);
+
+        self.set_info(id, info);
+        let node = &mut self[id];
+        node.folded = None;
+        node.categories = None;
+        self.refresh(id);
     }
-}
 
-impl<P: DuSource> Du<P> {
-    pub fn stats(&self) -> &Stats {
-        &self.0
+    /// Removes the subtrees of `ids` and renumbers the nodes left, keeping their order
+    fn prune(&mut self, ids: &[NodeId]) {
+        let mut removed = vec![false; self.nodes.len()];
+        for &id in ids {
+            let parent = self[id].parent;
+            self[parent].children.retain(|&c| c != id);
+            for (c, _) in self.dfs(id) {
+                removed[c.get()] = true;
+            }
+        }
+        // the new index of each node kept, found by counting those kept before it
+        let renumbered: Vec<_> = (removed.iter())
+            .scan(0, |kept, &removed| {
+                let index = *kept;
+                *kept += !removed as usize;
+                Some(NodeId::new(index))
+            })
+            .collect();
+        let mut removed = removed.into_iter();
+        self.nodes.retain(|_| !removed.next().unwrap_or_default());
+        for node in &mut self.nodes {
+            node.parent = renumbered[node.parent.get()];
+            for child in &mut node.children {
+                *child = renumbered[child.get()];
+            }
+        }
     }
 
-    pub fn begin(&mut self, path: impl Into<PathBuf>) {
-        self.1.enqueue(NodeId::ROOT, path.into());
-        self.1.begin();
+    /// Rebuilds the totals of `from` and its ancestors from their children
+    fn refresh(&mut self, from: NodeId) {
+        let mut p = from;
+        loop {
+            self.retotal(p);
+            if p == self[p].parent {
+                break;
+            }
+            p = self[p].parent;
+        }
     }
 
-    pub fn read_for(&mut self, dur: Duration) -> (usize, Duration) {
-        // TODO: consider reading the elapsed time every n seconds
-        let now = Instant::now();
-        let count = self.read(&mut |_, _| now.elapsed() < dur);
+    /// Rebuilds the totals of `id` from its children, which are taken as they are
+    fn retotal(&mut self, id: NodeId) {
+        self[id].reset();
+        for i in 0..self[id].children.len() {
+            self.absorb(id, self[id].children[i]);
+        }
+    }
 
-        (count, now.elapsed())
+    /// Adds the totals of `child` to `parent`, which must precede it
+    fn absorb(&mut self, parent: NodeId, child: NodeId) {
+        let (head, tail) = self.nodes.split_at_mut(child.get());
+        let (parent, node) = (&mut head[parent.get()], &tail[0]);
+        parent.info.apply(&node.info);
+
+        match (&node.categories, &node.info.category) {
+            (Some(categories), _) => {
+                for (category, usage) in &categories.total {
+                    parent.categories_mut().add(category, *usage);
+                }
+            }
+            (None, Some(category)) => {
+                parent.categories_mut().add(category, Usage::of(&node.info));
+            }
+            (None, None) => {}
+        }
     }
 
-    pub fn read(&mut self, with: &mut impl FnMut(&mut Stats, &mut P) -> bool) -> usize {
-        let Self(stats, provider) = self;
+    /// Checks that `nodes` form a tree before taking them as the stats
+    ///
+    /// Used when reading stats back, where the nodes come from elsewhere.
+    fn from_nodes(nodes: Vec<Node>) -> Result<Self, String> {
+        if nodes.first().is_none_or(|head| head.parent != NodeId::ROOT) {
+            return Err("missing head node".into());
+        }
 
-        let mut count = 0;
-        while let Some(entry) = provider.next_entry()
-            && with(stats, provider)
-        {
-            let is_dir = entry.info.kind == FileKind::Dir;
-            let next = stats.push(entry.parent, entry.info);
-            if is_dir {
-                provider.enqueue(next, entry.path);
+        // every node but the head must be the child of exactly one node
+        // which precedes it, as aggregating relies on that order
+        let mut seen = vec![false; nodes.len()];
+        for (i, node) in nodes.iter().enumerate() {
+            for &child in &node.children {
+                let c = child.get();
+                if c <= i || c >= nodes.len() {
+                    return Err(format!("node {i} has child {c} out of range"));
+                }
+                if nodes[c].parent.get() != i {
+                    return Err(format!("node {c} isn't a child of node {i}"));
+                }
+                if std::mem::replace(&mut seen[c], true) {
+                    return Err(format!("node {c} is listed twice"));
+                }
             }
-            count += 1;
+        }
+        if let Some(c) = seen.iter().skip(1).position(|&seen| !seen) {
+            return Err(format!("node {} has no parent", c + 1));
         }
 
-        count
+        let mut stats = Self {
+            nodes,
+            names: Vec::new(),
+            stale: false,
+        };
+        for i in 0..stats.nodes.len() {
+            let name = std::mem::take(&mut stats.nodes[i].info.name);
+            stats.set_name(NodeId::new(i), &name);
+        }
+        Ok(stats)
     }
-}
 
-pub trait DuSource {
-    type Error: Error;
+    fn push(&mut self, parent: NodeId, info: Info) -> NodeId {
+        self.propagate(parent, &info);
+        self.push_deferred(parent, info)
+    }
 
-    fn begin(&mut self);
-    fn finish(&mut self);
+    /// Adds `info` as a child of `parent`, leaving its ancestors' totals as they are
+    fn push_deferred(&mut self, parent: NodeId, mut info: Info) -> NodeId {
+        let id = NodeId::new(self.nodes.len());
+        self[parent].children.push(id);
 
-    fn next_entry(&mut self) -> Option<Entry>;
-    fn enqueue(&mut self, parent: NodeId, path: PathBuf);
+        let name = self.intern(&std::mem::take(&mut info.name));
+        self.nodes.push(Node::new(info, parent));
+        self[id].name = name;
+        id
+    }
 
-    fn errors(&self) -> &[Self::Error];
-}
+    /// Adds `info` to the totals of `parent` without giving it a node
+    ///
+    /// Returns `parent`, which then stands in for the entry.
+    fn fold(&mut self, parent: NodeId, info: &Info) -> NodeId {
+        self.fold_deferred(parent, info);
+        self.propagate(parent, info);
+        parent
+    }
 
-pub struct Entry {
-    parent: NodeId,
-    info: Info,
-    path: PathBuf,
+    /// Adds `info` to what's folded into `parent`, leaving the totals as they are
+    fn fold_deferred(&mut self, parent: NodeId, info: &Info) {
+        // the default counts itself as an other, which nothing folded is
+        let folded = (self[parent].folded).get_or_insert_with(|| {
+            Box::new(Info {
+                other: 0,
+                ..Info::default()
+            })
+        });
+        folded.apply(info);
+        if let Some(category) = &info.category {
+            let categories = self[parent].categories_mut();
+            Categories::add_to(&mut categories.folded, category, Usage::of(info));
+        }
+    }
+
+    /// Adds `info` to the totals of `from` and all of its ancestors
+    fn propagate(&mut self, from: NodeId, info: &Info) {
+        let mut p = from;
+        loop {
+            let node = &mut self[p];
+            node.info.apply(info);
+            if let Some(category) = &info.category {
+                node.categories_mut().add(category, Usage::of(info));
+            }
+            if p == node.parent {
+                break;
+            }
+            p = node.parent;
+        }
+    }
 }
 
-impl Entry {
-    pub fn new(parent: NodeId, info: Info, path: PathBuf) -> Self {
-        Self { parent, info, path }
+impl Index<NodeId> for Stats {
+    type Output = Node;
+
+    fn index(&self, index: NodeId) -> &Self::Output {
+        &self.nodes[index.get()]
     }
 }
 
-#[derive(Debug)]
-pub struct Stats {
-    nodes: Vec<Node>,
+impl IndexMut<NodeId> for Stats {
+    fn index_mut(&mut self, index: NodeId) -> &mut Self::Output {
+        &mut self.nodes[index.get()]
+    }
 }
 
-impl Default for Stats {
+/// Data of the caller's own for each node of a [`Stats`], made by [`Stats::map_nodes`]
+///
+/// Nodes are only ever added during a read, so the data can be kept up to
+/// date by [`NodeMap::extend`]. Anything which renumbers nodes, such as
+/// [`Du::remove`], [`Du::rescan`] or [`Du::reset`], leaves the data out of
+/// line with them, so it must then be mapped again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeMap<T>(Vec<T>);
+
+impl<T> Default for NodeMap<T> {
     fn default() -> Self {
-        let nodes = vec![Node::new(Info::default(), NodeId::ROOT)];
-        Self { nodes }
+        Self(Vec::new())
     }
 }
 
-impl Stats {
+impl<T> NodeMap<T> {
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn head(&self) -> &Node {
-        &self[NodeId::ROOT]
+    /// Maps the nodes added to `stats` since it was last mapped, such as during a read
+    pub fn extend(&mut self, stats: &Stats, mut f: impl FnMut(NodeId, &Node) -> T) {
+        for i in self.0.len()..stats.nodes.len() {
+            let id = NodeId::new(i);
+            self.0.push(f(id, &stats[id]));
+        }
     }
 
-    pub fn parent(&self, id: NodeId) -> &Node {
-        &self[self[id].parent]
+    pub fn get(&self, id: NodeId) -> Option<&T> {
+        self.0.get(id.get())
     }
 
-    fn push(&mut self, parent: NodeId, info: Info) -> NodeId {
-        let id = NodeId::new(self.nodes.len());
-        self[parent].children.push(id);
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut T> {
+        self.0.get_mut(id.get())
+    }
 
-        let mut p = parent;
-        while p != self[p].parent {
-            self[p].info.apply(&info);
-            p = self[p].parent;
-        }
-        self[p].info.apply(&info);
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 
-        self.nodes.push(Node::new(info, parent));
-        id
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Every node's data, in the order the nodes were added
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &T)> {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(i, data)| (NodeId::new(i), data))
     }
 }
 
-impl Index<NodeId> for Stats {
-    type Output = Node;
+impl<T> Index<NodeId> for NodeMap<T> {
+    type Output = T;
 
     fn index(&self, index: NodeId) -> &Self::Output {
-        &self.nodes[index.get()]
+        &self.0[index.get()]
     }
 }
 
-impl IndexMut<NodeId> for Stats {
+impl<T> IndexMut<NodeId> for NodeMap<T> {
     fn index_mut(&mut self, index: NodeId) -> &mut Self::Output {
-        &mut self.nodes[index.get()]
+        &mut self.0[index.get()]
+    }
+}
+
+/// The totals read so far, see [`Du::progress`]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// entries added to the tree
+    pub entries: usize,
+    /// apparent size of the entries
+    pub bytes: u64,
+    /// directories the source has read, which may be ahead of the entries added
+    pub dirs_read: usize,
+    /// directories found but not yet read, if the source knows
+    pub pending_dirs: Option<usize>,
+    /// errors hit by the source
+    pub errors: usize,
+    /// the time since the scan began
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// The entries added per second
+    pub fn rate(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.entries as f64 / secs,
+        }
+    }
+
+    /// The share of the directories found which have been read, from 0 to 1
+    ///
+    /// Directories are only counted once found, so this runs ahead of the
+    /// scan until the deepest parts of the tree are reached.
+    pub fn fraction(&self) -> Option<f64> {
+        let found = self.dirs_read + self.pending_dirs?;
+        Some(match found {
+            0 => 1.0,
+            found => self.dirs_read as f64 / found as f64,
+        })
     }
+
+    /// The time left, projected from the time taken to reach [`Progress::fraction`]
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction().filter(|&f| f > 0.0)?;
+        Duration::try_from_secs_f64(self.elapsed.as_secs_f64() * (1.0 - fraction) / fraction).ok()
+    }
+}
+
+/// The progress of a scan which began at `began`, see [`Du::progress`]
+fn progress(stats: &Stats, source: &impl DuSource, began: Option<Instant>) -> Progress {
+    Progress {
+        dirs_read: source.counters().dirs_read(),
+        pending_dirs: source.pending_dirs(),
+        errors: source.errors().len(),
+        elapsed: began.map_or(Duration::ZERO, |began| began.elapsed()),
+        ..stats.progress()
+    }
+}
+
+/// The result of [`Stats::bin_pack`]
+#[derive(Debug, Default)]
+pub struct Bins {
+    /// directories grouped so each bin's total fits in the bin size
+    pub bins: Vec<Vec<NodeId>>,
+    /// directories larger than a single bin
+    pub oversize: Vec<NodeId>,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 pub struct Node {
     info: Info,
+    /// where the name is kept by [`Stats`], set once the node is added
+    #[cfg_attr(feature = "serde", serde(skip))]
+    name: Name,
     parent: NodeId,
     children: Vec<NodeId>,
+    /// totals of the entries past the aggregate depth, which have no node
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    folded: Option<Box<Info>>,
+    /// totals of each category of file below this one, see [`Stats::categories`]
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    categories: Option<Box<Categories>>,
 }
 
 impl Node {
     pub fn new(info: Info, parent: NodeId) -> Self {
         Self {
             info,
+            name: Name::default(),
             parent,
             children: Vec::new(),
+            folded: None,
+            categories: None,
+        }
+    }
+
+    /// drops the totals of every sub-item with a node, keeping those folded
+    fn reset(&mut self) {
+        self.info.reset();
+        if let Some(folded) = &self.folded {
+            self.info.apply(folded);
+        }
+        if let Some(categories) = &mut self.categories {
+            categories.total.clone_from(&categories.folded);
+        }
+    }
+
+    fn categories_mut(&mut self) -> &mut Categories {
+        self.categories.get_or_insert_default()
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    /// The totals of the entries below this one that weren't given a node
+    ///
+    /// Only present when limited by [`Du::aggregate_depth`], and otherwise `None`.
+    pub fn folded(&self) -> Option<&Info> {
+        self.folded.as_deref()
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    pub fn parent_id(&self) -> NodeId {
+        self.parent
+    }
+}
+
+/// Where a node's name is within [`Stats::names`]
+#[derive(Debug, Default, Clone, Copy)]
+struct Name {
+    start: usize,
+    len: usize,
+}
+
+impl Name {
+    fn get(self, names: &[u8]) -> &OsStr {
+        let bytes = &names[self.start..self.start + self.len];
+        // SAFETY: names are only ever added whole, from the bytes of an `OsStr`
+        unsafe { OsStr::from_encoded_bytes_unchecked(bytes) }
+    }
+}
+
+/// The per-category totals of a node's sub-items
+///
+/// Kept as lists, since a directory rarely holds more than a few categories.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Categories {
+    /// every categorized sub-item, including those folded
+    total: Vec<(Category, Usage)>,
+    /// the sub-items folded into this node, as in [`Node::folded`]
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    folded: Vec<(Category, Usage)>,
+}
+
+impl Categories {
+    fn add(&mut self, category: &Category, usage: Usage) {
+        Self::add_to(&mut self.total, category, usage);
+    }
+
+    fn add_to(list: &mut Vec<(Category, Usage)>, category: &Category, usage: Usage) {
+        match list.iter_mut().find(|(c, _)| c == category) {
+            Some((_, total)) => total.add(usage),
+            None => list.push((category.clone(), usage)),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct NodeId(NonZero<usize>);
 
 impl NodeId {
@@ -168,19 +1791,59 @@ impl NodeId {
     fn get(self) -> usize {
         self.0.get() - 1
     }
+
+    /// The position of the node among every other, where the head is at zero
+    ///
+    /// Like the id itself, this only stays the same until nodes are renumbered.
+    pub fn index(self) -> usize {
+        self.get()
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Info {
-    pub name: Box<OsStr>,
+    /// the name of the entry, until [`Stats`] takes it, see [`Stats::name`]
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "ser::name", default, skip_serializing_if = "<OsStr>::is_empty")
+    )]
+    pub(crate) name: Box<OsStr>,
     pub kind: FileKind,
+    /// apparent size of sub-items, includes self
     pub size: u64,
+    /// apparent size of self alone
+    pub own: u64,
+    /// allocated size of sub-items, includes self
+    pub alloc: u64,
+    /// allocated size of self alone
+    pub own_alloc: u64,
     /// sub-files, includes self
-    pub files: u32,
+    pub files: u64,
     /// sub-dirs, includes self
-    pub dirs: u32,
-    /// unknown sub-items, includes self
-    pub other: u32,
+    pub dirs: u64,
+    /// sub-items which aren't files or dirs, includes self
+    pub other: u64,
+    /// where the entry is stored, if known
+    pub inode: Option<Inode>,
+    /// whether this is a repeated hardlink, whose size isn't counted
+    pub duplicate: bool,
+    /// repeated hardlinks among sub-items, includes self
+    pub duplicates: u64,
+    /// when self was last modified, if known
+    pub mtime: Option<SystemTime>,
+    /// the latest modification among sub-items, includes self
+    pub newest: Option<SystemTime>,
+    /// when self was last accessed, only recorded if [`Options::atime`] is set
+    pub atime: Option<SystemTime>,
+    /// whether self was last modified before [`Options::older_than`]
+    pub old: bool,
+    /// apparent size of sub-items which are old, includes self
+    pub old_size: u64,
+    /// who owns self, only recorded if [`Options::owners`] is set
+    pub owner: Option<Owner>,
+    /// the category of self, only for files sorted by [`Options::classifier`]
+    pub category: Option<Category>,
 }
 
 impl Default for Info {
@@ -191,40 +1854,578 @@ impl Default for Info {
 
 impl Info {
     pub fn new(name: impl Into<Box<OsStr>>, kind: FileKind, size: u64) -> Self {
-        Self {
+        let mut info = Self {
             name: name.into(),
             kind,
             size,
-            files: (kind == FileKind::File) as u32,
-            dirs: (kind == FileKind::Dir) as u32,
-            other: (kind == FileKind::Other) as u32,
+            own: size,
+            alloc: size,
+            own_alloc: size,
+            files: 0,
+            dirs: 0,
+            other: 0,
+            inode: None,
+            duplicate: false,
+            duplicates: 0,
+            mtime: None,
+            newest: None,
+            atime: None,
+            old: false,
+            old_size: 0,
+            owner: None,
+            category: None,
+        };
+        info.reset();
+        info
+    }
+
+    /// The size of sub-items, including self, as measured by `mode`
+    pub fn size_in(&self, mode: SizeMode) -> u64 {
+        match mode {
+            SizeMode::Apparent => self.size,
+            SizeMode::Allocated => self.alloc,
         }
     }
 
+    /// sets the size of self, for an info without sub-items
+    pub(crate) fn set_own(&mut self, size: u64, alloc: u64) {
+        self.own = size;
+        self.own_alloc = alloc;
+        self.reset();
+    }
+
+    /// marks this as a repeated hardlink, so its size no longer counts
+    pub(crate) fn set_duplicate(&mut self) {
+        self.duplicate = true;
+        self.set_own(0, 0);
+    }
+
+    /// drops everything contributed by sub-items
+    fn reset(&mut self) {
+        self.size = self.own;
+        self.alloc = self.own_alloc;
+        self.files = (self.kind == FileKind::File) as u64;
+        self.dirs = (self.kind == FileKind::Dir) as u64;
+        self.other = matches!(self.kind, FileKind::Symlink | FileKind::Other) as u64;
+        self.duplicates = self.duplicate as u64;
+        self.newest = self.mtime;
+        self.old_size = if self.old { self.own } else { 0 };
+    }
+
+    /// adds the totals of a sub-item, saturating so an overflow shows as `u64::MAX`
     fn apply(&mut self, info: &Info) {
-        self.size += info.size;
-        self.files += info.files;
-        self.dirs += info.dirs;
-        self.other += info.other;
+        self.size = self.size.saturating_add(info.size);
+        self.alloc = self.alloc.saturating_add(info.alloc);
+        self.files = self.files.saturating_add(info.files);
+        self.dirs = self.dirs.saturating_add(info.dirs);
+        self.other = self.other.saturating_add(info.other);
+        self.duplicates = self.duplicates.saturating_add(info.duplicates);
+        self.newest = self.newest.max(info.newest);
+        self.old_size = self.old_size.saturating_add(info.old_size);
+    }
+}
+
+/// How the size of an entry is measured
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SizeMode {
+    /// the length of the file's contents
+    #[default]
+    Apparent,
+    /// the space allocated on disk for the file, like `du`
+    ///
+    /// Falls back to the apparent size where the platform doesn't report it.
+    Allocated,
+}
+
+/// The user and group owning an entry, on Unix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// The space used by a group of entries, see [`Stats::by_owner`] and [`Stats::categories`]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Usage {
+    /// apparent size of the entries
+    pub size: u64,
+    /// allocated size of the entries
+    pub alloc: u64,
+    /// entries in the group, of any kind
+    pub entries: u64,
+}
+
+impl Usage {
+    /// The usage of `info` alone, without its sub-items
+    fn of(info: &Info) -> Self {
+        Self {
+            size: info.own,
+            alloc: info.own_alloc,
+            entries: 1,
+        }
+    }
+
+    /// The usage of `info` along with its sub-items
+    fn total(info: &Info) -> Self {
+        Self {
+            size: info.size,
+            alloc: info.alloc,
+            entries: (info.files)
+                .saturating_add(info.dirs)
+                .saturating_add(info.other),
+        }
+    }
+
+    fn add(&mut self, other: Usage) {
+        self.size = self.size.saturating_add(other.size);
+        self.alloc = self.alloc.saturating_add(other.alloc);
+        self.entries = self.entries.saturating_add(other.entries);
     }
 }
 
+/// The device and inode numbers of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Inode {
+    pub dev: u64,
+    pub ino: u64,
+    /// hard links to the inode
+    pub nlink: u64,
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FileKind {
     Dir,
     File,
+    Symlink,
     #[default]
     Other,
 }
 
+impl FileKind {
+    /// Classifies an entry, checking for a symlink, then a dir, then a file
+    ///
+    /// A symlink is always a [`FileKind::Symlink`], even when it also reports
+    /// being a dir or file.
+    pub const fn classify(is_symlink: bool, is_dir: bool, is_file: bool) -> Self {
+        match (is_symlink, is_dir, is_file) {
+            (true, _, _) => Self::Symlink,
+            (false, true, _) => Self::Dir,
+            (false, false, true) => Self::File,
+            (false, false, false) => Self::Other,
+        }
+    }
+}
+
 impl From<std::fs::FileType> for FileKind {
     fn from(value: std::fs::FileType) -> Self {
-        if value.is_dir() {
-            Self::Dir
-        } else if value.is_file() {
-            Self::File
-        } else {
-            Self::Other
+        Self::classify(value.is_symlink(), value.is_dir(), value.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test::{TempDir, scan, tree};
+
+    #[test]
+    fn classify_checks_symlink_then_dir_then_file() {
+        for is_dir in [false, true] {
+            for is_file in [false, true] {
+                assert_eq!(FileKind::classify(true, is_dir, is_file), FileKind::Symlink);
+            }
+        }
+        assert_eq!(FileKind::classify(false, true, true), FileKind::Dir);
+        assert_eq!(FileKind::classify(false, true, false), FileKind::Dir);
+        assert_eq!(FileKind::classify(false, false, true), FileKind::File);
+        assert_eq!(FileKind::classify(false, false, false), FileKind::Other);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn following_symlink_loops_terminates() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("du-symlink-loop");
+        dir.file("a/f", 10);
+        symlink(dir.path().join("a"), dir.path().join("a/up")).unwrap();
+        symlink(dir.path().join("a/me"), dir.path().join("a/me")).unwrap();
+
+        let du = scan(dir.path(), |du| du.symlinks(SymlinkPolicy::Follow));
+        let stats = du.stats();
+        // `up` leads back to a directory already read, and `me` to nothing
+        for name in ["up", "me"] {
+            let id = stats.find(&dir.path().join("a").join(name)).unwrap();
+            assert_eq!(stats.kind(id), FileKind::Symlink);
+            assert!(stats.children(id).is_empty());
+        }
+        assert_eq!(stats.head().info().files, 1);
+        assert_eq!(stats.dfs(NodeId::ROOT).count(), 5);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlinks_are_counted_once() {
+        let dir = TempDir::new("du-hardlinks");
+        let file = dir.file("a/x", 1000);
+        std::fs::create_dir(dir.path().join("b")).unwrap();
+        std::fs::hard_link(&file, dir.path().join("b/y")).unwrap();
+
+        let counted = scan(dir.path(), |_| {});
+        let deduped = scan(dir.path(), |du| du.dedup_hardlinks(true));
+        let (counted, deduped) = (counted.stats().head().info(), deduped.stats().head().info());
+        assert_eq!(counted.size - deduped.size, 1000);
+        assert_eq!(counted.duplicates, 0);
+        assert_eq!(deduped.duplicates, 1);
+        // the link is still an entry, only its size is left out
+        assert_eq!(deduped.files, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sparse_files_report_their_allocated_size() {
+        const LEN: u64 = 64 << 20;
+        let dir = TempDir::new("du-sparse");
+        let file = std::fs::File::create(dir.path().join("sparse")).unwrap();
+        file.set_len(LEN).unwrap();
+
+        let du = scan(dir.path(), |du| du.size_mode(SizeMode::Allocated));
+        let stats = du.stats();
+        let id = stats.find(&dir.path().join("sparse")).unwrap();
+        assert_eq!(stats.size(id, SizeMode::Apparent), LEN);
+        // nothing was written, so at most a block's been allocated for it
+        assert!(stats.size(id, SizeMode::Allocated) < LEN / 2);
+        assert_eq!(stats[id].info.alloc, stats[id].info.own_alloc);
+    }
+
+    #[test]
+    fn walks_visit_in_order() {
+        let dir = TempDir::new("du-walk-order");
+        dir.file("a/x", 1);
+        dir.file("a/y", 1);
+        dir.file("b/z", 1);
+        let mut du = scan(dir.path(), |_| {});
+        let stats = &mut du.0;
+        // entries are read in whatever order the filesystem lists them
+        let ids: Vec<_> = stats.dfs(NodeId::ROOT).map(|(id, _)| id).collect();
+        for id in ids {
+            stats.sort_children_by_name(id);
+        }
+
+        let names = |ids: Vec<NodeId>| -> Vec<_> {
+            let stats = &*stats;
+            (ids.into_iter().skip(1))
+                .map(|id| stats.name(id).to_str().unwrap().to_owned())
+                .collect()
+        };
+        let dfs = stats.dfs(NodeId::ROOT).map(|(id, _)| id).collect();
+        assert_eq!(names(dfs), ["a", "x", "y", "b", "z"]);
+        let bfs = stats.bfs(NodeId::ROOT).map(|(id, _)| id).collect();
+        assert_eq!(names(bfs), ["a", "b", "x", "y", "z"]);
+
+        let depths: Vec<_> = stats.walk(NodeId::ROOT).map(|(depth, ..)| depth).collect();
+        assert_eq!(depths, [0, 1, 2, 2, 1, 2]);
+        for (id, node) in stats.dfs(NodeId::ROOT).skip(1) {
+            assert!(stats.children(node.parent_id()).contains(&id));
+        }
+    }
+
+    #[test]
+    fn largest_are_found_in_order() {
+        let dir = TempDir::new("du-largest");
+        dir.file("a/big", 50_000);
+        dir.file("a/mid", 30_000);
+        dir.file("c", 20_000);
+        dir.file("t2", 1000);
+        dir.file("t1", 1000);
+        let du = scan(dir.path(), |_| {});
+        let stats = du.stats();
+        let names = |ids: &[NodeId]| -> Vec<_> {
+            (ids.iter())
+                .map(|&id| stats.name(id).to_str().unwrap())
+                .collect()
+        };
+
+        assert_eq!(names(&stats.largest_children(NodeId::ROOT, 2)), ["a", "c"]);
+        // ties are broken by name, and asking for more than there are is fine
+        let all = stats.largest_children(NodeId::ROOT, 10);
+        assert_eq!(names(&all), ["a", "c", "t1", "t2"]);
+        let descendants = stats.largest_descendants(NodeId::ROOT, 3);
+        assert_eq!(names(&descendants), ["a", "big", "mid"]);
+        let files: Vec<_> = stats.top_files(2).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(names(&files), ["big", "mid"]);
+        assert!(stats.largest_children(NodeId::ROOT, 0).is_empty());
+    }
+
+    #[test]
+    fn largest_keep_the_order_of_a_full_sort() {
+        let dir = TempDir::new("du-largest-sort");
+        // plenty of ties, both of sizes and of names in different directories
+        for i in 0..40 {
+            dir.file(&format!("{}/{}", i % 3, i % 7), i % 5 * 100);
+        }
+        let du = scan(dir.path(), |_| {});
+        let stats = du.stats();
+
+        let mut all: Vec<_> = (stats.dfs(NodeId::ROOT).skip(1))
+            .filter(|&(id, _)| stats.kind(id) == FileKind::File)
+            .map(|(id, _)| id)
+            .collect();
+        all.sort_by(|&a, &b| {
+            let size = |id: NodeId| stats[id].info.size;
+            (size(b).cmp(&size(a)))
+                .then_with(|| stats.name(a).cmp(stats.name(b)))
+                .then_with(|| a.get().cmp(&b.get()))
+        });
+        for n in 0..=all.len() + 1 {
+            let top: Vec<_> = stats.top_files(n).into_iter().map(|(id, _)| id).collect();
+            assert_eq!(top, all[..n.min(all.len())], "the {n} largest");
+        }
+    }
+
+    #[test]
+    fn max_depth_stops_descent() {
+        let dir = TempDir::new("du-max-depth");
+        dir.file("a/b/c/f", 10);
+        let du = scan(dir.path(), |du| du.max_depth(Some(1)));
+        let stats = du.stats();
+
+        // `b` is at the limit, so it's recorded but never read
+        let b = stats.find(&dir.path().join("a/b")).unwrap();
+        assert_eq!(stats.kind(b), FileKind::Dir);
+        assert!(stats.children(b).is_empty());
+        assert_eq!(stats.find(&dir.path().join("a/b/c")), None);
+        assert_eq!(stats.head().info().files, 0);
+
+        let unlimited = scan(dir.path(), |du| du.max_depth(None));
+        assert!(
+            unlimited
+                .stats()
+                .find(&dir.path().join("a/b/c/f"))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn filters_exclude_subdirs() {
+        let dir = TempDir::new("du-filter");
+        dir.file("keep/f", 10);
+        dir.file("target/debug/f", 1000);
+        dir.file("target.txt", 100);
+
+        let by_predicate = scan(dir.path(), |du| {
+            du.filter(|path, _| path.file_name().is_none_or(|n| n != "target"))
+        });
+        let by_pattern = scan(dir.path(), |du| {
+            du.path_filter(filter::Filter::new().exclude("target"))
+        });
+        for du in [by_predicate, by_pattern] {
+            let stats = du.stats();
+            // neither counted nor descended into, leaving a similar name alone
+            assert_eq!(stats.find(&dir.path().join("target")), None);
+            assert!(stats.find(&dir.path().join("target.txt")).is_some());
+            assert_eq!(stats.head().info().files, 2);
+            assert_eq!(du.1.counters().dirs_read(), 2);
         }
     }
+
+    #[test]
+    fn path_filters_match_from_each_root() {
+        let dir = TempDir::new("du-path-filter");
+        dir.file("a/target/f", 10);
+        dir.file("b/target/f", 10);
+        dir.file("b/a/target/f", 10);
+
+        let filter = || filter::Filter::new().exclude("/a/target");
+        let one = scan(dir.path(), |du| du.path_filter(filter()));
+        assert_eq!(one.stats().find(&dir.path().join("a/target")), None);
+        assert!(one.stats().find(&dir.path().join("b/a/target")).is_some());
+
+        // with several roots, each is matched from its own, so `b/a/target`
+        // is only left out from `b`
+        let mut many = Du::new(st::Source::default());
+        many.path_filter(filter());
+        many.begin_many([dir.path().to_path_buf(), dir.path().join("b")]);
+        many.read(&mut |_, _| true);
+        assert_eq!(many.stats().head().info().files, 3);
+    }
+
+    #[test]
+    fn one_filesystem_reads_the_same_device() {
+        let dir = TempDir::new("du-one-filesystem");
+        dir.file("a/b/f", 10);
+        let du = scan(dir.path(), |du| du.one_filesystem(true));
+        assert!(du.stats().find(&dir.path().join("a/b/f")).is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn one_filesystem_skips_other_devices() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dev = |path: &str| std::fs::metadata(path).map(|md| md.dev()).ok();
+        // only where `/proc` is mounted apart from the root, as it usually is
+        if dev("/proc").is_none() || dev("/proc") == dev("/") {
+            return;
+        }
+        let du = scan(Path::new("/"), |du| {
+            du.one_filesystem(true);
+            du.max_depth(Some(1));
+        });
+        let stats = du.stats();
+        let proc = stats.find(Path::new("/proc")).unwrap();
+        assert!(stats.children(proc).is_empty());
+    }
+
+    #[test]
+    fn totals_saturate_rather_than_wrap() {
+        let mut stats = Stats::new();
+        let mut huge = Info::new(OsStr::new("huge"), FileKind::File, u64::MAX - 1);
+        huge.files = u64::MAX;
+        stats.push(NodeId::ROOT, huge);
+        stats.push(
+            NodeId::ROOT,
+            Info::new(OsStr::new("more"), FileKind::File, 10),
+        );
+
+        let head = stats.head().info();
+        assert_eq!(head.size, u64::MAX);
+        assert_eq!(head.alloc, u64::MAX);
+        assert_eq!(head.files, u64::MAX);
+        // sizes are found again from each entry's own, counts from their kinds
+        stats.recompute_aggregates();
+        assert_eq!(stats.head().info().size, u64::MAX);
+        assert_eq!(stats.head().info().files, 2);
+    }
+
+    #[test]
+    fn progress_only_grows() {
+        let dir = tree("du-progress", 20, 10);
+        let mut du = Du::new(mt::Source::default());
+        du.begin(dir.path());
+        let mut seen = Vec::new();
+        du.read_with_progress(|progress| {
+            seen.push(progress);
+            true
+        });
+
+        for pair in seen.windows(2) {
+            let [a, b] = pair else { unreachable!() };
+            assert!(
+                a.entries <= b.entries && a.bytes <= b.bytes,
+                "{a:?} then {b:?}"
+            );
+            assert!(
+                a.dirs_read <= b.dirs_read && a.elapsed <= b.elapsed,
+                "{a:?} then {b:?}"
+            );
+        }
+        // the last call sees every entry, while the source is done once read returns
+        let last = seen.last().unwrap();
+        assert_eq!(last.entries, du.stats().progress().entries);
+        assert_eq!(last.bytes, du.stats().head().info().size);
+        assert_eq!(du.progress().pending_dirs, Some(0));
+        assert_eq!(du.progress().fraction(), Some(1.0));
+    }
+
+    #[test]
+    fn errors_carry_the_path_failed_on() {
+        let dir = TempDir::new("du-errors");
+        dir.file("ok/f", 10);
+        dir.file("gone/f", 10);
+        // removed once found, but before it's read, which fails even as root
+        let du = scan(dir.path(), |du| {
+            du.filter(|path, _| {
+                if path.ends_with("gone") {
+                    std::fs::remove_dir_all(path).unwrap();
+                }
+                true
+            })
+        });
+
+        let [error] = du.errors() else {
+            panic!("expected one error, found {:?}", du.errors());
+        };
+        let gone = dir.path().join("gone");
+        assert_eq!(error.path, gone);
+        assert_eq!(error.op, ScanOp::ReadDir);
+        assert_eq!(error.source.kind(), io::ErrorKind::NotFound);
+        assert_eq!(Some(error.node), du.stats().find(&gone));
+        assert!(error.to_string().starts_with(&*gone.to_string_lossy()));
+        // the rest of the scan goes on
+        assert!(du.stats().find(&dir.path().join("ok/f")).is_some());
+    }
+
+    #[test]
+    fn rescans_after_reset_match() {
+        use std::collections::BTreeMap;
+
+        fn summary(stats: &Stats) -> BTreeMap<PathBuf, (FileKind, u64, u64, u64)> {
+            (stats.dfs(NodeId::ROOT))
+                .map(|(id, node)| {
+                    let info = node.info();
+                    (
+                        stats.path_of(id),
+                        (info.kind, info.size, info.files, info.dirs),
+                    )
+                })
+                .collect()
+        }
+        fn rescan(mut du: Du<impl DuSource>, path: &Path) {
+            du.begin(path);
+            du.read(&mut |_, _| true);
+            let first = summary(du.stats());
+            let progress = du.progress();
+
+            du.reset();
+            assert_eq!(du.stats().dfs(NodeId::ROOT).count(), 1);
+            du.begin(path);
+            du.read(&mut |_, _| true);
+            assert_eq!(summary(du.stats()), first);
+            assert_eq!(du.progress().entries, progress.entries);
+            assert_eq!(du.progress().dirs_read, progress.dirs_read);
+        }
+
+        let dir = tree("du-rescan", 12, 8);
+        rescan(Du::new(st::Source::default()), dir.path());
+        rescan(Du::new(mt::Source::default()), dir.path());
+    }
+
+    #[test]
+    fn only_deferred_scans_leave_totals_stale() {
+        fn read(path: &Path, defer: bool) -> (Du<st::Source>, bool) {
+            let mut du = Du::new(st::Source::default());
+            du.defer_totals(defer);
+            du.begin(path);
+            let mut stale = false;
+            du.read(&mut |stats, _| {
+                stale |= stats.stale;
+                true
+            });
+            (du, stale)
+        }
+        fn summary(stats: &Stats) -> Vec<(PathBuf, u64, u64, u64)> {
+            let mut nodes: Vec<_> = (stats.dfs(NodeId::ROOT))
+                .map(|(id, node)| {
+                    let info = node.info();
+                    (stats.path_of(id), info.size, info.files, info.dirs)
+                })
+                .collect();
+            nodes.sort();
+            nodes
+        }
+
+        let dir = tree("du-stale", 8, 6);
+        let (kept, kept_stale) = read(dir.path(), false);
+        let (deferred, deferred_stale) = read(dir.path(), true);
+        assert!(!kept_stale);
+        assert!(deferred_stale);
+        // found once the read finished, rather than as it went
+        assert!(!deferred.stats().stale);
+        assert_eq!(summary(deferred.stats()), summary(kept.stats()));
+    }
 }
@@ -0,0 +1,364 @@
+//! finding files with the same contents after a scan
+//!
+//! Only files sharing a size are ever read. Their starts are hashed first,
+//! and only those which still match are hashed in full, so most files are
+//! never read past their first block.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::{self, Read},
+    num::NonZero,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering::Relaxed},
+    },
+    thread,
+};
+
+use crate::du::{FileKind, NodeId, ScanError, ScanOp, Stats};
+
+/// Files with the same contents, see [`Finder::find`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Set {
+    /// the size of each of the files
+    pub size: u64,
+    /// the space which would be freed by keeping only the first file
+    pub wasted: u64,
+    /// the files, in the order they were walked
+    pub files: Vec<NodeId>,
+}
+
+/// The files of a scan with the same contents as another, found by [`Finder::find`]
+#[derive(Debug, Default)]
+pub struct Duplicates {
+    sets: Vec<Set>,
+    errors: Vec<ScanError>,
+}
+
+impl Duplicates {
+    /// Each set of files with the same contents, from most to least wasted
+    pub fn sets(&self) -> &[Set] {
+        &self.sets
+    }
+
+    /// The space which would be freed by keeping only the first file of each set
+    pub fn wasted(&self) -> u64 {
+        self.sets.iter().map(|set| set.wasted).sum()
+    }
+
+    /// The space wasted within each directory of `stats`, the scan these were found in
+    ///
+    /// Every file of a set but the first is counted by each of its ancestors,
+    /// so the head has the whole of [`Duplicates::wasted`]. Directories
+    /// without any duplicates are left out.
+    pub fn wasted_by_dir(&self, stats: &Stats) -> HashMap<NodeId, u64> {
+        let mut wasted = HashMap::new();
+        for set in &self.sets {
+            for &id in &set.files[1..] {
+                let alloc = stats[id].info().own_alloc;
+                let mut p = id;
+                while p != stats[p].parent {
+                    p = stats[p].parent;
+                    *wasted.entry(p).or_default() += alloc;
+                }
+            }
+        }
+        wasted
+    }
+
+    /// The files which couldn't be read, so were left out of every set
+    pub fn errors(&self) -> &[ScanError] {
+        &self.errors
+    }
+}
+
+/// the bytes hashed of each file before any are hashed in full
+const PARTIAL: u64 = 4096;
+
+/// Finds the files of a scan with the same contents, see [`Finder::find`]
+///
+/// Contents are compared by their hashes, which by default are those of
+/// [`RandomState`], so they're only meaningful within a single search.
+#[derive(Debug, Clone)]
+pub struct Finder<S = RandomState> {
+    hasher: S,
+    threads: NonZero<usize>,
+    min_size: u64,
+}
+
+impl Default for Finder {
+    fn default() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            threads: thread::available_parallelism().unwrap_or(NonZero::<usize>::MIN),
+            min_size: 1,
+        }
+    }
+}
+
+impl Finder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S: BuildHasher + Sync> Finder<S> {
+    /// Hashes contents with `hasher`, such as one which is faster or stronger
+    pub fn hasher<T: BuildHasher + Sync>(self, hasher: T) -> Finder<T> {
+        Finder {
+            hasher,
+            threads: self.threads,
+            min_size: self.min_size,
+        }
+    }
+
+    /// Reads at most `threads` files at once, which defaults to one per core
+    pub fn threads(mut self, threads: NonZero<usize>) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Leaves out files smaller than `bytes`, which defaults to leaving out empty ones
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes.max(1);
+        self
+    }
+
+    /// Groups the files of `stats` by their contents, reading them from where they were scanned
+    ///
+    /// Hardlinks to the same inode share their contents without taking any
+    /// more space, so only the first of them is counted. Files folded by
+    /// [`Du::aggregate_depth`](crate::du::Du::aggregate_depth) have no nodes
+    /// of their own, so they're never compared.
+    pub fn find(&self, stats: &Stats) -> Duplicates {
+        let mut by_size: HashMap<u64, Vec<NodeId>> = HashMap::new();
+        let mut inodes = HashMap::new();
+        for (_, id, node) in stats.walk(NodeId::ROOT) {
+            let info = node.info();
+            if info.kind != FileKind::File || info.own < self.min_size {
+                continue;
+            }
+            if let Some(inode) = info.inode
+                && inodes.insert((inode.dev, inode.ino), id).is_some()
+            {
+                continue;
+            }
+            by_size.entry(info.own).or_default().push(id);
+        }
+
+        let mut errors = Vec::new();
+        let candidates = by_size.into_values().filter(|ids| ids.len() > 1);
+        let groups = self.split(stats, candidates.collect(), Some(PARTIAL), &mut errors);
+        // the rest of a file is only hashed if it has a rest
+        let (mut groups, large): (Vec<_>, _) =
+            (groups.into_iter()).partition(|ids: &Vec<NodeId>| stats[ids[0]].info().own <= PARTIAL);
+        groups.extend(self.split(stats, large, None, &mut errors));
+
+        // files are kept in the order they were walked, so the first is the shallowest
+        let mut sets: Vec<_> = (groups.into_iter())
+            .map(|files| Set {
+                size: stats[files[0]].info().own,
+                wasted: files[1..]
+                    .iter()
+                    .map(|&id| stats[id].info().own_alloc)
+                    .sum(),
+                files,
+            })
+            .collect();
+        sets.sort_by(|a, b| {
+            (b.wasted.cmp(&a.wasted)).then(a.files[0].get().cmp(&b.files[0].get()))
+        });
+
+        Duplicates { sets, errors }
+    }
+
+    /// Splits each group by the hashes of the first `limit` bytes of its files
+    ///
+    /// Only groups of more than one file are kept. A file which can't be read
+    /// is left out, along with an error.
+    fn split(
+        &self,
+        stats: &Stats,
+        groups: Vec<Vec<NodeId>>,
+        limit: Option<u64>,
+        errors: &mut Vec<ScanError>,
+    ) -> Vec<Vec<NodeId>> {
+        let jobs: Vec<_> = (groups.iter().enumerate())
+            .flat_map(|(group, ids)| ids.iter().map(move |&id| (group, id)))
+            .map(|(group, id)| (group, id, stats.path_of(id)))
+            .collect();
+        let hashes = self.hash_all(&jobs, limit);
+
+        let mut split: HashMap<(usize, u64), Vec<NodeId>> = HashMap::new();
+        for ((group, id, path), hash) in jobs.into_iter().zip(hashes) {
+            match hash {
+                Ok(hash) => split.entry((group, hash)).or_default().push(id),
+                Err(e) => errors.push(ScanError::new(&path, ScanOp::Hash, id, e)),
+            }
+        }
+        split.into_values().filter(|ids| ids.len() > 1).collect()
+    }
+
+    /// Hashes the file of each job, reading as many at once as there are threads
+    fn hash_all(
+        &self,
+        jobs: &[(usize, NodeId, PathBuf)],
+        limit: Option<u64>,
+    ) -> Vec<io::Result<u64>> {
+        let next = AtomicUsize::new(0);
+        let hashes = Mutex::new((0..jobs.len()).map(|_| None).collect::<Vec<_>>());
+        thread::scope(|s| {
+            for _ in 0..self.threads.get().min(jobs.len()) {
+                s.spawn(|| {
+                    let mut buf = vec![0; 64 * 1024];
+                    loop {
+                        let i = next.fetch_add(1, Relaxed);
+                        let Some((_, _, path)) = jobs.get(i) else {
+                            break;
+                        };
+                        let hash = self.hash(path, limit, &mut buf);
+                        hashes.lock().unwrap()[i] = Some(hash);
+                    }
+                });
+            }
+        });
+        (hashes.into_inner().unwrap().into_iter())
+            .map(|hash| hash.expect("every job is hashed"))
+            .collect()
+    }
+
+    fn hash(&self, path: &Path, limit: Option<u64>, buf: &mut [u8]) -> io::Result<u64> {
+        let file = File::open(path)?;
+        let mut file = file.take(limit.unwrap_or(u64::MAX));
+        let mut hasher = self.hasher.build_hasher();
+        loop {
+            match file.read(buf) {
+                Ok(0) => return Ok(hasher.finish()),
+                Ok(len) => hasher.write(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::util::test::{TempDir, scan};
+
+    /// Writes `len` bytes of `x` to `rel`, with the last of them being `last`
+    fn write(dir: &TempDir, rel: &str, len: usize, last: u8) {
+        let path = dir.file(rel, len);
+        let mut contents = vec![b'x'; len];
+        contents[len - 1] = last;
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn paths(stats: &Stats, dir: &TempDir, files: &[NodeId]) -> BTreeSet<String> {
+        (files.iter())
+            .map(|&id| {
+                stats
+                    .path_of(id)
+                    .strip_prefix(dir.path())
+                    .unwrap()
+                    .to_owned()
+            })
+            .map(|path| path.to_str().unwrap().replace('\\', "/"))
+            .collect()
+    }
+
+    #[test]
+    fn sets_hold_files_with_the_same_contents() {
+        let dir = TempDir::new("dedupe");
+        let large = PARTIAL as usize + 100;
+        write(&dir, "a/one", 10, b'x');
+        write(&dir, "b/two", 10, b'x');
+        write(&dir, "c/d/three", 10, b'x');
+        // the same size, but differing within the part hashed first
+        write(&dir, "a/short", 10, b'y');
+        // and beyond it, where only the second one matches the first
+        write(&dir, "a/large", large, b'x');
+        write(&dir, "b/large", large, b'y');
+        write(&dir, "c/large", large, b'x');
+        // a size held by only one file is never read
+        write(&dir, "unique", 11, b'x');
+        // nor are empty files
+        dir.file("a/empty", 0);
+        dir.file("b/empty", 0);
+
+        let du = scan(dir.path(), |_| {});
+        let stats = du.stats();
+        let dupes = Finder::new().find(stats);
+        assert!(dupes.errors().is_empty());
+
+        let mut sets: Vec<_> = (dupes.sets().iter())
+            .map(|set| (set.size, paths(stats, &dir, &set.files)))
+            .collect();
+        sets.sort();
+        let set = |files: &[&str]| files.iter().map(|f| f.to_string()).collect::<BTreeSet<_>>();
+        assert_eq!(
+            sets,
+            [
+                (10, set(&["a/one", "b/two", "c/d/three"])),
+                (large as u64, set(&["a/large", "c/large"])),
+            ]
+        );
+
+        // everything but the first of each set is wasted
+        let alloc = |id: NodeId| stats[id].info().own_alloc;
+        for set in dupes.sets() {
+            let all: u64 = set.files.iter().map(|&id| alloc(id)).sum();
+            assert_eq!(set.wasted, all - alloc(set.files[0]));
+        }
+        let wasted: Vec<_> = dupes.sets().iter().map(|set| set.wasted).collect();
+        assert!(wasted.is_sorted_by(|a, b| a >= b));
+        assert_eq!(dupes.wasted(), wasted.iter().sum::<u64>());
+
+        // which each ancestor of the rest counts
+        let mut by_dir = HashMap::new();
+        for set in dupes.sets() {
+            for &id in &set.files[1..] {
+                for ancestor in stats.path_of(id).ancestors().skip(1) {
+                    let Some(p) = stats.find(ancestor) else {
+                        break;
+                    };
+                    *by_dir.entry(p).or_insert(0) += alloc(id);
+                }
+            }
+        }
+        assert_eq!(dupes.wasted_by_dir(stats), by_dir);
+        assert_eq!(by_dir[&NodeId::ROOT], dupes.wasted());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlinks_arent_wasted() {
+        let dir = TempDir::new("dedupe-hardlinks");
+        write(&dir, "a/one", 10, b'x');
+        std::fs::hard_link(dir.path().join("a/one"), dir.path().join("link")).unwrap();
+
+        let du = scan(dir.path(), |_| {});
+        let dupes = Finder::new().find(du.stats());
+        assert_eq!(dupes.sets(), []);
+        assert_eq!(dupes.wasted(), 0);
+        assert!(dupes.wasted_by_dir(du.stats()).is_empty());
+
+        // but a copy still is, and only once however many links it has
+        write(&dir, "copy", 10, b'x');
+        let du = scan(dir.path(), |_| {});
+        let stats = du.stats();
+        let dupes = Finder::new().find(stats);
+        let [set] = dupes.sets() else {
+            panic!("not one set: {:?}", dupes.sets());
+        };
+        assert_eq!(set.files.len(), 2);
+        assert!(paths(stats, &dir, &set.files).contains("copy"));
+        assert_eq!(dupes.wasted(), stats[set.files[1]].info().own_alloc);
+    }
+}
@@ -0,0 +1,264 @@
+//! comparing two scans of the same tree
+
+use std::{collections::HashMap, ffi::OsStr, ops::Index};
+
+use crate::du::{FileKind, Info, NodeId, Stats};
+
+/// How an entry differs between two scans
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// only in the new scan
+    Added,
+    /// only in the old scan
+    Removed,
+    /// in both scans, with a different kind, size or count
+    Changed,
+    /// in both scans, and the same in each
+    Unchanged,
+}
+
+/// The difference in an entry between two scans, see [`diff`]
+///
+/// Deltas are new minus old, with a missing entry counting as zero.
+#[derive(Debug)]
+pub struct Delta {
+    pub name: Box<OsStr>,
+    /// the kind in the new scan, or the old one if it was removed
+    pub kind: FileKind,
+    pub change: Change,
+    pub old: Option<NodeId>,
+    pub new: Option<NodeId>,
+    /// the distance from the head, which is at zero
+    pub depth: usize,
+    pub size: i64,
+    pub alloc: i64,
+    pub files: i64,
+    pub dirs: i64,
+    children: Vec<usize>,
+}
+
+impl Delta {
+    /// The indices of the deltas of this entry's children
+    pub fn children(&self) -> &[usize] {
+        &self.children
+    }
+}
+
+/// A tree of deltas between two scans, stored depth-first from the head
+///
+/// Entries are matched by path, so a moved entry shows as being removed from
+/// one place and added to another.
+#[derive(Debug)]
+pub struct Diff {
+    deltas: Vec<Delta>,
+}
+
+impl Diff {
+    pub fn head(&self) -> &Delta {
+        &self.deltas[0]
+    }
+
+    /// Every delta, depth-first starting with the head
+    pub fn deltas(&self) -> &[Delta] {
+        &self.deltas
+    }
+
+    /// The deltas of entries which were added, removed or changed
+    pub fn changes(&self) -> impl Iterator<Item = &Delta> {
+        self.deltas.iter().filter(|d| d.change != Change::Unchanged)
+    }
+
+    /// The `n` deltas which grew the most, from most to least
+    ///
+    /// Sizes include contents, so ancestors of growing entries show up as well.
+    pub fn largest_growth(&self, n: usize) -> Vec<&Delta> {
+        let mut deltas: Vec<_> = self.deltas.iter().skip(1).filter(|d| d.size > 0).collect();
+        deltas.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        deltas.truncate(n);
+        deltas
+    }
+}
+
+impl Index<usize> for Diff {
+    type Output = Delta;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.deltas[index]
+    }
+}
+
+/// Compares `old` and `new`, matching their entries by path
+///
+/// The heads are matched with each other, so both scans should have begun at
+/// the same path.
+pub fn diff(old: &Stats, new: &Stats) -> Diff {
+    let mut deltas: Vec<Delta> = Vec::new();
+    // the pairs left to compare, along with the index of their parent's delta
+    let mut stack = vec![(Some(NodeId::ROOT), Some(NodeId::ROOT), 0, None::<usize>)];
+    while let Some((o, n, depth, parent)) = stack.pop() {
+        let index = deltas.len();
+        if let Some(parent) = parent {
+            deltas[parent].children.push(index);
+        }
+        deltas.push(delta(old, new, o, n, depth));
+
+        let pairs = pair_children(old, new, o, n).into_iter().rev();
+        stack.extend(pairs.map(|(o, n)| (o, n, depth + 1, Some(index))));
+    }
+
+    Diff { deltas }
+}
+
+fn delta(old: &Stats, new: &Stats, o: Option<NodeId>, n: Option<NodeId>, depth: usize) -> Delta {
+    let (o_info, n_info) = (o.map(|o| &old[o].info), n.map(|n| &new[n].info));
+    let info = n_info.or(o_info).expect("an entry is in at least one scan");
+    let name = match n {
+        Some(n) => new.name(n),
+        None => old.name(o.expect("an entry is in at least one scan")),
+    };
+    let change = match (o_info, n_info) {
+        (None, _) => Change::Added,
+        (_, None) => Change::Removed,
+        (Some(a), Some(b)) if differs(a, b) => Change::Changed,
+        _ => Change::Unchanged,
+    };
+    let field = |f: fn(&Info) -> u64| {
+        let (a, b) = (o_info.map_or(0, f), n_info.map_or(0, f));
+        (i128::from(b) - i128::from(a)).clamp(i64::MIN.into(), i64::MAX.into()) as i64
+    };
+
+    Delta {
+        name: name.into(),
+        kind: info.kind,
+        change,
+        old: o,
+        new: n,
+        depth,
+        size: field(|i| i.size),
+        alloc: field(|i| i.alloc),
+        files: field(|i| i.files),
+        dirs: field(|i| i.dirs),
+        children: Vec::new(),
+    }
+}
+
+fn differs(a: &Info, b: &Info) -> bool {
+    a.kind != b.kind
+        || a.size != b.size
+        || a.alloc != b.alloc
+        || a.files != b.files
+        || a.dirs != b.dirs
+        || a.other != b.other
+}
+
+/// Pairs up the children of `o` and `n` by name, in the new scan's order
+/// followed by those only in the old one
+fn pair_children(
+    old: &Stats,
+    new: &Stats,
+    o: Option<NodeId>,
+    n: Option<NodeId>,
+) -> Vec<(Option<NodeId>, Option<NodeId>)> {
+    let old_children = o.map_or(&[][..], |o| old.children(o));
+    let new_children = n.map_or(&[][..], |n| new.children(n));
+
+    let mut by_name: HashMap<&OsStr, NodeId> =
+        (old_children.iter()).map(|&c| (old.name(c), c)).collect();
+    let mut pairs: Vec<_> = (new_children.iter())
+        .map(|&c| (by_name.remove(new.name(c)), Some(c)))
+        .collect();
+    pairs.extend(
+        (old_children.iter())
+            .filter(|&c| by_name.contains_key(old.name(*c)))
+            .map(|&c| (Some(c), None)),
+    );
+
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::util::test::{TempDir, scan};
+
+    /// Every delta by its path from the head
+    fn by_path(diff: &Diff) -> HashMap<PathBuf, &Delta> {
+        let mut paths = HashMap::new();
+        let mut stack = vec![(PathBuf::new(), 0)];
+        while let Some((path, index)) = stack.pop() {
+            let delta = &diff[index];
+            for &child in delta.children() {
+                stack.push((path.join(&*diff[child].name), child));
+            }
+            assert!(paths.insert(path, delta).is_none());
+        }
+        paths
+    }
+
+    #[test]
+    fn rescans_show_what_changed() {
+        let dir = TempDir::new("diff");
+        dir.file("keep/same", 10);
+        dir.file("keep/grow", 100);
+        dir.file("gone", 20);
+        let old = scan(dir.path(), |_| {});
+
+        dir.file("keep/new", 7);
+        dir.file("keep/grow", 150);
+        std::fs::remove_file(dir.path().join("gone")).unwrap();
+        let new = scan(dir.path(), |_| {});
+
+        let diff = diff(old.stats(), new.stats());
+        let deltas = by_path(&diff);
+        assert_eq!(deltas.len(), diff.deltas().len());
+        let get = |path: &str| {
+            let d = deltas[Path::new(path)];
+            (d.change, d.kind, d.size, d.files, d.dirs)
+        };
+        assert_eq!(
+            get("keep/same"),
+            (Change::Unchanged, FileKind::File, 0, 0, 0)
+        );
+        assert_eq!(
+            get("keep/grow"),
+            (Change::Changed, FileKind::File, 50, 0, 0)
+        );
+        assert_eq!(get("keep/new"), (Change::Added, FileKind::File, 7, 1, 0));
+        assert_eq!(get("gone"), (Change::Removed, FileKind::File, -20, -1, 0));
+        assert_eq!(deltas[Path::new("keep/new")].old, None);
+        assert_eq!(deltas[Path::new("gone")].new, None);
+
+        // directories change by what's within them, along with their own size
+        let own = |du: &crate::du::Du<_>, path: &str| {
+            let stats = du.stats();
+            let id = stats.find(&dir.path().join(path)).unwrap();
+            stats[id].info.own as i64
+        };
+        let keep_own = own(&new, "keep") - own(&old, "keep");
+        assert_eq!(get("keep").2, 57 + keep_own);
+        assert_eq!(get("keep").3, 1);
+        let root_own =
+            (new.stats().head().info().own as i64) - (old.stats().head().info().own as i64);
+        assert_eq!(get("").2, 37 + keep_own + root_own);
+        assert_eq!(get("").3, 0);
+
+        let mut changes: Vec<_> = (diff.changes())
+            .filter(|d| d.kind == FileKind::File)
+            .map(|d| (d.name.to_str().unwrap(), d.change))
+            .collect();
+        changes.sort_by_key(|&(name, _)| name);
+        assert_eq!(
+            changes,
+            [
+                ("gone", Change::Removed),
+                ("grow", Change::Changed),
+                ("new", Change::Added),
+            ]
+        );
+        // the head is left out, as its growth is that of the whole scan
+        let growth: Vec<_> = (diff.largest_growth(2).iter()).map(|d| &*d.name).collect();
+        assert_eq!(growth, [OsStr::new("keep"), OsStr::new("grow")]);
+    }
+}
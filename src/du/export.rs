@@ -0,0 +1,356 @@
+//! writing scan results as JSON, for use by other tools
+//!
+//! Both formats are streamed while walking the tree, so nothing is built up in
+//! memory. Writers should be buffered, as each value is written separately.
+
+use std::{
+    ffi::OsStr,
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::du::{FileKind, Info, NodeId, Stats};
+
+/// Writes `stats` in the JSON export format of [ncdu](https://dev.yorhel.nl/ncdu)
+///
+/// The head is named by the path the scan began at. Entries folded by
+/// [`Du::aggregate_depth`](crate::du::Du::aggregate_depth) have no nodes, so
+/// they are left out entirely.
+pub fn ncdu(stats: &Stats, mut w: impl Write) -> io::Result<()> {
+    // the minor version is 2 rather than 0, as the `nlink` of hardlinks only
+    // came with it
+    let timestamp = (SystemTime::now().duration_since(UNIX_EPOCH)).map_or(0, |d| d.as_secs());
+    write!(
+        w,
+        r#"[1,2,{{"progname":"dustat","progver":"{}","timestamp":{timestamp}}}"#,
+        env!("CARGO_PKG_VERSION"),
+    )?;
+
+    // the depths of the directories whose arrays are still open
+    let mut open = Vec::new();
+    for (depth, id, node) in stats.walk(NodeId::ROOT) {
+        while open.last().is_some_and(|&d| d >= depth) {
+            open.pop();
+            w.write_all(b"]")?;
+        }
+        // every entry follows either the header or its directory's own info
+        w.write_all(b",")?;
+
+        let info = node.info();
+        let kind = kind_of(id, info);
+        if kind == FileKind::Dir {
+            open.push(depth);
+            w.write_all(b"[")?;
+        }
+        w.write_all(br#"{"name":"#)?;
+        write_str(&mut w, stats.name(id))?;
+        write!(w, r#","asize":{},"dsize":{}"#, info.own, info.own_alloc)?;
+        if let Some(inode) = info.inode {
+            write!(w, r#","dev":{},"ino":{}"#, inode.dev, inode.ino)?;
+            if kind != FileKind::Dir && inode.nlink > 1 {
+                write!(w, r#","hlnkc":true,"nlink":{}"#, inode.nlink)?;
+            }
+        }
+        if let Some(mtime) = info.mtime.and_then(unix_secs) {
+            write!(w, r#","mtime":{mtime}"#)?;
+        }
+        if matches!(kind, FileKind::Symlink | FileKind::Other) {
+            w.write_all(br#","notreg":true"#)?;
+        }
+        w.write_all(b"}")?;
+    }
+
+    for _ in open {
+        w.write_all(b"]")?;
+    }
+    w.write_all(b"]")
+}
+
+/// Writes `stats` as a tree of nested JSON objects
+///
+/// Each entry has its `name`, `kind`, and every size and count of
+/// [`Info`], with directories also having their `children`. Times are in
+/// seconds since the Unix epoch, and left out when unknown.
+pub fn json(stats: &Stats, mut w: impl Write) -> io::Result<()> {
+    // the depths of the directories whose children are still open, and
+    // whether any children have been written yet
+    let mut open: Vec<(usize, bool)> = Vec::new();
+    for (depth, id, node) in stats.walk(NodeId::ROOT) {
+        while open.last().is_some_and(|&(d, _)| d >= depth) {
+            open.pop();
+            w.write_all(b"]}")?;
+        }
+        if let Some((_, written)) = open.last_mut()
+            && std::mem::replace(written, true)
+        {
+            w.write_all(b",")?;
+        }
+
+        let info = node.info();
+        let kind = kind_of(id, info);
+        write_object(&mut w, stats.name(id), kind, info)?;
+        match kind {
+            FileKind::Dir => {
+                open.push((depth, false));
+                w.write_all(br#","children":["#)?;
+            }
+            _ => w.write_all(b"}")?,
+        }
+    }
+
+    for _ in open {
+        w.write_all(b"]}")?;
+    }
+    Ok(())
+}
+
+/// The kind of the node `id`, where the head is the directory scanned
+fn kind_of(id: NodeId, info: &Info) -> FileKind {
+    match id == NodeId::ROOT {
+        true => FileKind::Dir,
+        false => info.kind,
+    }
+}
+
+/// Writes the fields of `info`, leaving the object open
+fn write_object(w: &mut impl Write, name: &OsStr, kind: FileKind, info: &Info) -> io::Result<()> {
+    let kind = match kind {
+        FileKind::Dir => "dir",
+        FileKind::File => "file",
+        FileKind::Symlink => "symlink",
+        FileKind::Other => "other",
+    };
+    w.write_all(br#"{"name":"#)?;
+    write_str(w, name)?;
+    write!(
+        w,
+        r#","kind":"{kind}","size":{},"own":{},"alloc":{},"own_alloc":{},"files":{},"dirs":{},"other":{},"duplicates":{}"#,
+        info.size,
+        info.own,
+        info.alloc,
+        info.own_alloc,
+        info.files,
+        info.dirs,
+        info.other,
+        info.duplicates,
+    )?;
+    if info.duplicate {
+        w.write_all(br#","duplicate":true"#)?;
+    }
+    if let Some(mtime) = info.mtime.and_then(unix_secs) {
+        write!(w, r#","mtime":{mtime}"#)?;
+    }
+    if let Some(newest) = info.newest.and_then(unix_secs) {
+        write!(w, r#","newest":{newest}"#)?;
+    }
+    if let Some(atime) = info.atime.and_then(unix_secs) {
+        write!(w, r#","atime":{atime}"#)?;
+    }
+    if info.old_size > 0 {
+        write!(w, r#","old_size":{}"#, info.old_size)?;
+    }
+    if let Some(category) = &info.category {
+        w.write_all(br#","category":"#)?;
+        write_str(w, OsStr::new(category.name()))?;
+    }
+    if let Some(owner) = info.owner {
+        write!(w, r#","uid":{},"gid":{}"#, owner.uid, owner.gid)?;
+    }
+    Ok(())
+}
+
+/// The whole seconds since the Unix epoch, or `None` for earlier times
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// Writes `s` as a quoted JSON string
+///
+/// Like ncdu, names which aren't valid UTF-8 are written as their raw bytes
+/// on Unix. Elsewhere they are made lossy.
+fn write_str(w: &mut impl Write, s: &OsStr) -> io::Result<()> {
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(s);
+    #[cfg(not(unix))]
+    let lossy = s.to_string_lossy();
+    #[cfg(not(unix))]
+    let bytes = lossy.as_bytes();
+
+    w.write_all(b"\"")?;
+    let mut start = 0;
+    for (i, &b) in bytes.iter().enumerate() {
+        let escaped: &[u8] = match b {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0..0x20 | 0x7f => &[],
+            _ => continue,
+        };
+        w.write_all(&bytes[start..i])?;
+        match escaped {
+            [] => write!(w, "\\u{b:04x}")?,
+            escaped => w.write_all(escaped)?,
+        }
+        start = i + 1;
+    }
+    w.write_all(&bytes[start..])?;
+    w.write_all(b"\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::util::test::{TempDir, scan};
+
+    fn write(export: fn(&Stats, &mut Vec<u8>) -> io::Result<()>, stats: &Stats) -> Vec<u8> {
+        let mut out = Vec::new();
+        export(stats, &mut out).unwrap();
+        out
+    }
+
+    fn parse(out: &[u8]) -> Value {
+        serde_json::from_slice(out).unwrap()
+    }
+
+    /// The name, sizes, and whether it's a directory, of each entry of the
+    /// ncdu directory `dir`, in the order written
+    fn ncdu_entries(dir: &Value, entries: &mut Vec<(String, u64, u64, bool)>) {
+        let entry = |info: &Value, is_dir| {
+            let name = info["name"].as_str().unwrap().to_owned();
+            (
+                name,
+                info["asize"].as_u64().unwrap(),
+                info["dsize"].as_u64().unwrap(),
+                is_dir,
+            )
+        };
+        let [info, children @ ..] = &dir.as_array().unwrap()[..] else {
+            panic!("an empty directory array");
+        };
+        entries.push(entry(info, true));
+        for child in children {
+            match child.is_array() {
+                true => ncdu_entries(child, entries),
+                false => entries.push(entry(child, false)),
+            }
+        }
+    }
+
+    /// What [`ncdu_entries`] should find for `stats`
+    fn stats_entries(stats: &Stats) -> Vec<(String, u64, u64, bool)> {
+        (stats.walk(NodeId::ROOT))
+            .map(|(_, id, node)| {
+                let info = node.info();
+                let name = stats.name(id).to_string_lossy().into_owned();
+                (
+                    name,
+                    info.own,
+                    info.own_alloc,
+                    kind_of(id, info) == FileKind::Dir,
+                )
+            })
+            .collect()
+    }
+
+    fn tree() -> (TempDir, Stats) {
+        let dir = TempDir::new("export");
+        dir.file("a/b/c", 3);
+        dir.file("a/d", 40);
+        dir.file("e", 500);
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        let du = scan(dir.path(), |_| {});
+        (dir, du.0)
+    }
+
+    #[test]
+    fn ncdu_nests_directories_under_the_header() {
+        let (dir, stats) = tree();
+        let out = parse(&write(|s, w| ncdu(s, w), &stats));
+        let [major, minor, header, root] = &out.as_array().unwrap()[..] else {
+            panic!("not a header and one root: {out}");
+        };
+        assert_eq!((major, minor), (&Value::from(1), &Value::from(2)));
+        assert_eq!(header["progname"], "dustat");
+        assert!(header["timestamp"].is_u64());
+
+        assert_eq!(root[0]["name"], dir.path().to_str().unwrap());
+        let mut entries = Vec::new();
+        ncdu_entries(root, &mut entries);
+        assert_eq!(entries, stats_entries(&stats));
+        // the sizes of a directory are its own, leaving ncdu to add them up
+        let e = entries.iter().find(|e| e.0 == "e").unwrap();
+        assert_eq!((e.1, e.3), (500, false));
+        let empty = (root.as_array().unwrap().iter()).find(|c| c[0]["name"] == "empty");
+        assert_eq!(empty.unwrap().as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn json_nests_children_with_their_totals() {
+        let (_dir, stats) = tree();
+        let out = parse(&write(|s, w| json(s, w), &stats));
+
+        fn check(stats: &Stats, id: NodeId, value: &Value) {
+            let info = &stats[id].info;
+            assert_eq!(value["name"], *stats.name(id).to_string_lossy());
+            assert_eq!(value["size"], info.size);
+            assert_eq!(value["own"], info.own);
+            assert_eq!(value["alloc"], info.alloc);
+            assert_eq!(value["files"], info.files);
+            assert_eq!(value["dirs"], info.dirs);
+
+            let children = stats.children(id);
+            match value.get("children") {
+                Some(values) => {
+                    let values = values.as_array().unwrap();
+                    assert_eq!(values.len(), children.len());
+                    for (&child, value) in children.iter().zip(values) {
+                        check(stats, child, value);
+                    }
+                }
+                None => assert!(children.is_empty() && value["kind"] != "dir"),
+            }
+        }
+        assert_eq!(out["kind"], "dir");
+        assert_eq!(out["files"], 3);
+        check(&stats, NodeId::ROOT, &out);
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let name = "q\"b\\n\nr\rt\t\u{1}\u{1f}\u{7f}é";
+        let mut stats = Stats::new();
+        stats.set_name(NodeId::ROOT, OsStr::new("/r"));
+        stats.push(NodeId::ROOT, Info::new(OsStr::new(name), FileKind::File, 1));
+
+        let ncdu_out = parse(&write(|s, w| ncdu(s, w), &stats));
+        assert_eq!(ncdu_out[3][1]["name"], name);
+        let json_out = parse(&write(|s, w| json(s, w), &stats));
+        assert_eq!(json_out["children"][0]["name"], name);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_which_arent_utf8_are_written_raw() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut stats = Stats::new();
+        stats.set_name(NodeId::ROOT, OsStr::new("/r"));
+        let name = OsStr::from_bytes(b"a\xff\"b");
+        stats.push(NodeId::ROOT, Info::new(name, FileKind::File, 1));
+
+        for out in [
+            write(|s, w| ncdu(s, w), &stats),
+            write(|s, w| json(s, w), &stats),
+        ] {
+            assert!(out.windows(6).any(|w| w == b"\"a\xff\\\"b"));
+            // which is only valid JSON once made lossy
+            assert!(serde_json::from_slice::<Value>(&out).is_err());
+            let lossy = parse(String::from_utf8_lossy(&out).as_bytes());
+            assert!(lossy.to_string().contains("a\u{fffd}\\\"b"));
+        }
+    }
+}
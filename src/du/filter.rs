@@ -0,0 +1,422 @@
+//! glob based filtering of the entries read
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::du::{FileKind, Info};
+
+/// Excludes entries matching any of a set of patterns, or not matching any includes
+///
+/// Patterns are globs, where `*` matches within a component, `?` matches a
+/// single character of one, and `**` matches across components. A pattern
+/// without a separator is matched against the entry's name, and otherwise against
+/// its path from the root of the scan, as an ignore file's patterns are from its
+/// directory. So `**/target` excludes every `target` anywhere while `/proc`
+/// excludes only the one at the root, whichever path the scan begins at.
+/// A trailing `/` makes a pattern match only directories.
+///
+/// Includes only apply to files and other non-directories, since excluding a
+/// directory would prevent the files within it from ever being read.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leaves out every entry matching `pattern`
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(Rule::parse(pattern));
+        self
+    }
+
+    /// Leaves out `prefix` and everything within it
+    ///
+    /// Unlike a pattern, the prefix is compared by components as-is against the
+    /// whole path of each entry, so it begins with the root of the scan.
+    pub fn exclude_prefix(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.exclude.push(Rule::Prefix(prefix.into()));
+        self
+    }
+
+    /// Keeps only the non-directories matching `pattern`, or any other include
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(Rule::parse(pattern));
+        self
+    }
+
+    /// Whether the entry at `path` is kept, where it's `depth` levels below the root of the scan
+    ///
+    /// Like the depths of [`Options::max_depth`], the entries of the root
+    /// itself are at zero.
+    ///
+    /// [`Options::max_depth`]: crate::du::Options::max_depth
+    pub fn test(&self, path: &Path, depth: usize, info: &Info) -> bool {
+        let within = (path.ancestors().nth(depth + 1))
+            .and_then(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path);
+        let matches = |r: &Rule, is_dir| match r {
+            Rule::Prefix(_) => r.matches(path, is_dir),
+            _ => r.matches(within, is_dir),
+        };
+
+        let is_dir = info.kind == FileKind::Dir;
+        if self.exclude.iter().any(|r| matches(r, is_dir)) {
+            return false;
+        }
+        is_dir || self.include.is_empty() || self.include.iter().any(|r| matches(r, false))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Rule {
+    Name { glob: Glob, dir_only: bool },
+    Path { glob: Glob, dir_only: bool },
+    Prefix(PathBuf),
+}
+
+impl Rule {
+    fn parse(pattern: &str) -> Self {
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(rest) if !rest.is_empty() => (rest, true),
+            _ => (pattern, false),
+        };
+        match pattern.bytes().any(is_separator) {
+            // a leading separator only anchors the pattern to the root
+            true => Self::Path {
+                glob: Glob::parse(pattern.strip_prefix('/').unwrap_or(pattern)),
+                dir_only,
+            },
+            false => Self::Name {
+                glob: Glob::parse(pattern),
+                dir_only,
+            },
+        }
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        match self {
+            Self::Name { dir_only, .. } | Self::Path { dir_only, .. } if *dir_only && !is_dir => {
+                false
+            }
+            Self::Name { glob, .. } => path
+                .file_name()
+                .is_some_and(|name| glob.matches(name.as_encoded_bytes())),
+            Self::Path { glob, .. } => glob.matches(path.as_os_str().as_encoded_bytes()),
+            Self::Prefix(prefix) => path.starts_with(prefix),
+        }
+    }
+}
+
+/// The rules of the ignore files which apply within a directory, see [`Options::ignore_files`]
+///
+/// Each file's rules apply to what's within its own directory, with the
+/// closest file's rules coming first, as git does.
+///
+/// [`Options::ignore_files`]: crate::du::Options::ignore_files
+#[derive(Debug)]
+pub(crate) struct Ignores {
+    /// the directory holding the files
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+    /// the rules of the files found further up
+    parent: Option<Arc<Ignores>>,
+}
+
+impl Ignores {
+    /// Reads the ignore files named `names` in `dir`, placing them over `parent`
+    ///
+    /// This is `None` if there are no rules in any of them. Files which
+    /// can't be read are treated as empty.
+    pub(crate) fn read(dir: &Path, names: &[OsString], parent: Option<Arc<Self>>) -> Option<Self> {
+        let rules: Vec<_> = (names.iter())
+            .filter_map(|name| std::fs::read(dir.join(name)).ok())
+            .flat_map(|file| {
+                let file = String::from_utf8_lossy(&file).into_owned();
+                file.lines()
+                    .filter_map(IgnoreRule::parse)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        (!rules.is_empty()).then(|| Self {
+            base: dir.to_path_buf(),
+            rules,
+            parent,
+        })
+    }
+
+    /// Whether the entry at `path` is ignored by the last rule matching it
+    pub(crate) fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignores = Some(self);
+        while let Some(Self {
+            base,
+            rules,
+            parent,
+        }) = ignores
+        {
+            if let Ok(rel) = path.strip_prefix(base)
+                && let Some(rule) = rules.iter().rev().find(|r| r.rule.matches(rel, is_dir))
+            {
+                return !rule.negated;
+            }
+            ignores = parent.as_deref();
+        }
+        false
+    }
+}
+
+/// A single line of an ignore file
+#[derive(Debug)]
+struct IgnoreRule {
+    rule: Rule,
+    /// whether this keeps what an earlier rule ignored, written with a leading `!`
+    negated: bool,
+}
+
+impl IgnoreRule {
+    /// Parses a line of an ignore file, which is `None` if it has no rule
+    ///
+    /// Trailing spaces are always trimmed, even when escaped. A pattern with a
+    /// separator before its end is matched against the path from the ignore
+    /// file's directory, and otherwise against the name of each entry within
+    /// it. Character classes aren't supported, so patterns which use them
+    /// never match.
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches(' ');
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(rest) => (rest, true),
+            None => (pattern, false),
+        };
+        if pattern.is_empty() {
+            return None;
+        }
+        let rule = match pattern.bytes().any(is_separator) {
+            // a leading separator only anchors the pattern to the file's directory
+            true => Rule::Path {
+                glob: Glob::parse(pattern.strip_prefix('/').unwrap_or(pattern)),
+                dir_only,
+            },
+            false => Rule::Name {
+                glob: Glob::parse(pattern),
+                dir_only,
+            },
+        };
+        Some(Self { rule, negated })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Byte(u8),
+    /// `/`, or either separator on Windows
+    Separator,
+    /// `?`
+    One,
+    /// `*`
+    Within,
+    /// `**`
+    Across,
+    /// `**/`, which may also match nothing at all
+    Dirs,
+}
+
+/// A compiled glob, matched against the encoded bytes of a path
+///
+/// Every special character is ASCII, so matching bytes is the same as
+/// matching characters, without paths needing to be valid UTF-8.
+#[derive(Debug, Clone)]
+struct Glob(Vec<Token>);
+
+impl Glob {
+    fn parse(pattern: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut bytes = pattern.bytes().peekable();
+        while let Some(b) = bytes.next() {
+            tokens.push(match b {
+                b'?' => Token::One,
+                b'*' if bytes.next_if_eq(&b'*').is_some() => {
+                    match bytes.next_if(|&b| is_separator(b)) {
+                        Some(_) => Token::Dirs,
+                        None => Token::Across,
+                    }
+                }
+                b'*' => Token::Within,
+                b if is_separator(b) => Token::Separator,
+                b => Token::Byte(b),
+            });
+        }
+        Self(tokens)
+    }
+
+    fn matches(&self, text: &[u8]) -> bool {
+        let tokens = &self.0;
+        // `next[j]` is whether the tokens after the current one match `text[j..]`
+        let mut next = vec![false; text.len() + 1];
+        next[text.len()] = true;
+        let mut cur = vec![false; text.len() + 1];
+
+        for &token in tokens.iter().rev() {
+            // whether `next` matches after any separator at or past `j`
+            let mut after_separator = false;
+            for j in (0..=text.len()).rev() {
+                let byte = text.get(j).copied();
+                cur[j] = match token {
+                    Token::Byte(b) => byte == Some(b) && next[j + 1],
+                    Token::Separator => byte.is_some_and(is_separator) && next[j + 1],
+                    Token::One => byte.is_some_and(|b| !is_separator(b)) && next[j + 1],
+                    Token::Within => {
+                        next[j] || (byte.is_some_and(|b| !is_separator(b)) && cur[j + 1])
+                    }
+                    Token::Across => next[j] || (byte.is_some() && cur[j + 1]),
+                    // nothing, or anything up to and including a separator
+                    Token::Dirs => {
+                        after_separator |= byte.is_some_and(is_separator) && next[j + 1];
+                        next[j] || after_separator
+                    }
+                };
+            }
+            std::mem::swap(&mut cur, &mut next);
+        }
+
+        next[0]
+    }
+}
+
+fn is_separator(b: u8) -> bool {
+    b == b'/' || (cfg!(windows) && b == b'\\')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        Glob::parse(pattern).matches(text.as_bytes())
+    }
+
+    fn info(kind: FileKind) -> Info {
+        Info::new(OsStr::new(""), kind, 0)
+    }
+
+    #[test]
+    fn globs_match_within_and_across_components() {
+        assert!(glob("*.rs", "main.rs"));
+        assert!(glob("*.rs", ".rs"));
+        assert!(!glob("*.rs", "src/main.rs"));
+        assert!(!glob("*.rs", "main.rsx"));
+
+        assert!(glob("?.rs", "a.rs"));
+        assert!(!glob("?.rs", ".rs"));
+        assert!(!glob("?.rs", "ab.rs"));
+        assert!(!glob("a?b", "a/b"));
+
+        assert!(glob("src/**", "src/a/b/c.rs"));
+        assert!(glob("a**z", "a/b/z"));
+        assert!(glob("a/**/z", "a/z"));
+        assert!(glob("a/**/z", "a/b/c/z"));
+        assert!(!glob("a/**/z", "a/bz"));
+
+        // a leading `**/` matches at any depth, including none
+        assert!(glob("**/target", "target"));
+        assert!(glob("**/target", "a/b/target"));
+        assert!(!glob("**/target", "a/xtarget"));
+        assert!(!glob("**/target", "target/a"));
+    }
+
+    #[test]
+    fn trailing_separators_only_match_directories() {
+        for pattern in ["target/", "a/target/"] {
+            let rule = Rule::parse(pattern);
+            let path = Path::new(pattern.trim_end_matches('/'));
+            assert!(rule.matches(path, true), "{pattern}");
+            assert!(!rule.matches(path, false), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn path_patterns_are_anchored_to_the_root() {
+        let filter = Filter::new().exclude("/proc").exclude("a/*.log");
+        let dir = info(FileKind::Dir);
+        let file = info(FileKind::File);
+
+        for root in ["/", "/home", "relative", "./relative"] {
+            let root = Path::new(root);
+            assert!(!filter.test(&root.join("proc"), 0, &dir));
+            assert!(filter.test(&root.join("x/proc"), 1, &dir));
+            assert!(!filter.test(&root.join("a/b.log"), 1, &file));
+            assert!(filter.test(&root.join("x/a/b.log"), 2, &file));
+        }
+        // unlike a pattern, a prefix is the whole path
+        let prefix = Filter::new().exclude_prefix("/home/a");
+        assert!(!prefix.test(Path::new("/home/a/b"), 1, &file));
+        assert!(prefix.test(Path::new("/home/ab"), 0, &file));
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let filter = Filter::new().include("*.rs").exclude("gen_*");
+        let file = info(FileKind::File);
+        assert!(filter.test(Path::new("r/main.rs"), 0, &file));
+        assert!(!filter.test(Path::new("r/main.txt"), 0, &file));
+        assert!(!filter.test(Path::new("r/gen_a.rs"), 0, &file));
+
+        // directories are never left out by includes, only by excludes
+        let dir = info(FileKind::Dir);
+        assert!(filter.test(Path::new("r/src"), 0, &dir));
+        assert!(!filter.test(Path::new("r/gen_src"), 0, &dir));
+
+        let any = Filter::new().include("*.rs").include("*.toml");
+        assert!(any.test(Path::new("r/Cargo.toml"), 0, &file));
+        assert!(Filter::new().test(Path::new("r/a"), 0, &file));
+    }
+
+    #[test]
+    fn later_ignore_rules_override_earlier_ones() {
+        let rules = |lines: &[&str]| {
+            let rules = lines.iter().filter_map(|l| IgnoreRule::parse(l)).collect();
+            Ignores {
+                base: PathBuf::from("/r"),
+                rules,
+                parent: None,
+            }
+        };
+        let ignores = rules(&["*.log", "!keep.log", "# a comment", "", "/build/"]);
+        assert!(ignores.ignores(Path::new("/r/a.log"), false));
+        assert!(ignores.ignores(Path::new("/r/a/b.log"), false));
+        assert!(!ignores.ignores(Path::new("/r/keep.log"), false));
+        assert!(ignores.ignores(Path::new("/r/build"), true));
+        assert!(!ignores.ignores(Path::new("/r/build"), false));
+        assert!(!ignores.ignores(Path::new("/r/a/build"), true));
+
+        // a rule may also ignore again what was kept
+        let again = rules(&["*.log", "!keep.log", "keep.*"]);
+        assert!(again.ignores(Path::new("/r/keep.log"), false));
+
+        // the closest file's rules come first, leaving the rest for the parent's
+        let child = Ignores {
+            base: PathBuf::from("/r/a"),
+            rules: vec![IgnoreRule::parse("!b.log").unwrap()],
+            parent: Some(Arc::new(rules(&["*.log"]))),
+        };
+        assert!(!child.ignores(Path::new("/r/a/b.log"), false));
+        assert!(child.ignores(Path::new("/r/a/c.log"), false));
+        assert!(child.ignores(Path::new("/r/c.log"), false));
+    }
+}
@@ -1,119 +1,429 @@
 use std::{
-    collections::VecDeque,
-    io::{Error, ErrorKind, Result},
-    path::PathBuf,
+    iter,
+    path::{Path, PathBuf},
     sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
-        mpsc,
+        Arc, Condvar, Mutex,
+        atomic::{
+            AtomicBool, AtomicUsize,
+            Ordering::{Relaxed, SeqCst},
+        },
+        mpsc::{self, RecvTimeoutError},
     },
-    thread,
+    task::Poll,
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
 use crate::{
-    du::{DuSource, Entry, NodeId},
-    util,
+    du::WAIT,
+    du::{Counters, DuSource, Entry, Inode, NextEntry, NodeId, Options, ScanError},
+    util::{self, Batches, Message, ScanState},
 };
 
-type Handle<T> = Arc<Mutex<T>>;
-type TaskHandle = Handle<VecDeque<(NodeId, PathBuf)>>;
+type Task = (NodeId, PathBuf, usize);
 
-pub struct Source {
+/// State shared between the source and its workers
+#[derive(Default)]
+struct Shared {
     running: AtomicBool,
-    tasks: TaskHandle,
+    /// tasks enqueued but not yet finished, whether queued or in progress
+    pending: AtomicUsize,
+    /// workers which haven't yet stopped, each sending a message as it does
+    live: AtomicUsize,
+    /// tasks enqueued by the source, which workers take in batches
+    injector: Injector<Task>,
+    /// workers waiting for a task, woken by `wake`
+    sleeping: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    scan: ScanState,
+}
+
+impl Shared {
+    fn push_task(&self, task: Task) {
+        self.pending.fetch_add(1, SeqCst);
+        self.injector.push(task);
+        // a worker about to sleep checks the injector after counting itself,
+        // so either it sees the task or it's seen here
+        if self.sleeping.load(SeqCst) > 0 {
+            let _guard = self.sleep.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+
+    /// Takes a task from `local`, then the injector, then another worker
+    fn find_task(&self, local: &Worker<Task>, stealers: &[Stealer<Task>]) -> Option<Task> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                (self.injector.steal_batch_and_pop(local))
+                    .or_else(|| stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|s| !s.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    /// Waits for a task to be enqueued, or for the scan to stop
+    fn sleep(&self) {
+        let guard = self.sleep.lock().unwrap();
+        self.sleeping.fetch_add(1, SeqCst);
+        if self.injector.is_empty() && self.running.load(Relaxed) {
+            // timed out now and then, in case a task is only left to steal
+            let _ = self.wake.wait_timeout(guard, Duration::from_millis(10));
+        }
+        self.sleeping.fetch_sub(1, SeqCst);
+    }
 
-    tx_entries: mpsc::Sender<Result<Entry>>,
-    rx_entries: mpsc::Receiver<Result<Entry>>,
+    /// Wakes every worker, such as for them to stop
+    fn wake_all(&self) {
+        let _guard = self.sleep.lock().unwrap();
+        self.wake.notify_all();
+    }
 
-    errors: Vec<Error>,
+    /// no work is left to be done, nor is any in progress
+    fn is_idle(&self) -> bool {
+        !self.running.load(Relaxed) || self.pending.load(SeqCst) == 0
+    }
+}
+
+/// Reads directories on a pool of worker threads, in the background
+///
+/// Workers are spawned by [`DuSource::begin`] and run until every directory
+/// is read, so entries can be taken as they arrive while the scan goes on.
+/// [`DuSource::finish`] stops them early, joining each before returning.
+pub struct Source {
+    shared: Arc<Shared>,
+    options: Arc<Options>,
+    workers: Vec<JoinHandle<()>>,
+
+    tx_entries: mpsc::SyncSender<Message>,
+    rx_entries: mpsc::Receiver<Message>,
+
+    /// the most entries sent in a single message
+    batch_size: usize,
+    /// the batches taken out of `rx_entries`
+    received: Batches,
 }
 
 impl Default for Source {
     fn default() -> Self {
-        let (tx_entries, rx_entries) = mpsc::channel();
+        Self::new(Self::DEFAULT_BOUND)
+    }
+}
+
+impl Source {
+    pub const DEFAULT_BOUND: usize = 1 << 16;
+    pub const DEFAULT_BATCH_SIZE: usize = 256;
+
+    /// Creates a source which buffers at most `bound` unread entries
+    ///
+    /// Workers wait for entries to be read once the buffer is full, which keeps
+    /// memory bounded when they outpace the consumer. The bound is rounded up
+    /// to a whole number of batches, of which there's always at least one.
+    pub fn new(bound: usize) -> Self {
+        Self::with_batch_size(bound, Self::DEFAULT_BATCH_SIZE)
+    }
+
+    /// Creates a source whose workers send entries in batches of `batch_size`
+    ///
+    /// Larger batches cut the overhead of sending each entry, while smaller
+    /// ones let entries be read sooner. A directory's last batch is sent as
+    /// soon as it's read, however small.
+    pub fn with_batch_size(bound: usize, batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        // a bound of zero would leave no room for the messages which wake the source
+        let capacity = bound.div_ceil(batch_size).max(1);
+        let (tx_entries, rx_entries) = mpsc::sync_channel(capacity);
 
         Self {
-            running: AtomicBool::new(false),
-            tasks: TaskHandle::default(),
+            shared: Arc::default(),
+            options: Arc::default(),
+            workers: Vec::new(),
             tx_entries,
             rx_entries,
-            errors: Vec::new(),
+            batch_size,
+            received: Batches::default(),
         }
     }
 }
 
-impl Source {
-    fn handle_err<T>(&mut self, res: Result<T>) -> Option<T> {
-        match res {
-            Ok(value) => Some(value),
-            Err(e) => {
-                self.errors.push(e);
-                None
-            }
-        }
+impl Drop for Source {
+    fn drop(&mut self) {
+        self.finish();
     }
 }
 
 impl DuSource for Source {
-    type Error = Error;
+    type Error = ScanError;
 
     fn begin(&mut self) {
+        self.finish();
+
         let parallelism = thread::available_parallelism().map_or(1, |n| n.get());
-        let threads = AtomicUsize::new(0);
-        let running = &self.running;
-        let tasks = &self.tasks;
-        let entries = &self.tx_entries;
-
-        self.running.store(true, Relaxed);
-        thread::scope(|s| {
-            for _ in 0..parallelism {
-                s.spawn(|| {
-                    run_thread(&threads, running, tasks, entries);
-                });
-            }
-        });
-        self.running.store(false, Relaxed);
+        let locals: Vec<_> = (0..parallelism).map(|_| Worker::new_fifo()).collect();
+        let stealers: Arc<[_]> = locals.iter().map(Worker::stealer).collect();
+
+        self.shared.running.store(true, Relaxed);
+        self.shared.live.store(parallelism, SeqCst);
+        self.workers = (locals.into_iter())
+            .map(|local| {
+                let shared = self.shared.clone();
+                let stealers = stealers.clone();
+                let options = self.options.clone();
+                let entries = self.tx_entries.clone();
+                let batch_size = self.batch_size;
+                thread::spawn(move || {
+                    run_thread(&shared, &local, &stealers, &options, &entries, batch_size)
+                })
+            })
+            .collect();
     }
 
     fn finish(&mut self) {
-        self.running.store(false, Relaxed);
+        self.shared.running.store(false, Relaxed);
+        self.shared.wake_all();
+        // workers may be waiting on a full buffer, so it's drained until they
+        // stop, and each sends a message once it has, if there's room for it
+        while self.shared.live.load(SeqCst) > 0 {
+            let _ = self.rx_entries.recv_timeout(WAIT);
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.finish();
+        while self.rx_entries.try_recv().is_ok() {}
+        self.received.clear();
+
+        let shared = Arc::get_mut(&mut self.shared).expect("workers should be joined");
+        while !shared.injector.steal().is_empty() {}
+        *shared.pending.get_mut() = 0;
+        shared.scan.reset();
     }
 
     fn next_entry(&mut self) -> Option<Entry> {
-        self.handle_err(self.rx_entries.try_recv().unwrap())
+        util::next_entry(self)
+    }
+
+    fn poll_entry(&mut self) -> NextEntry {
+        self.wait_entry(Duration::ZERO)
     }
 
-    fn enqueue(&mut self, parent: NodeId, path: PathBuf) {
-        self.tasks.lock().unwrap().push_back((parent, path));
+    fn wait_entry(&mut self, timeout: Duration) -> NextEntry {
+        let (shared, cancel, rx) = (&self.shared, &self.options.cancel, &self.rx_entries);
+        let next = self.received.next(|| {
+            // checked first, as everything sent before going idle is then received
+            let idle = shared.is_idle() || cancel.is_cancelled();
+            // the last task to finish sends a message, so going idle ends the wait
+            match idle {
+                true => Poll::Ready(rx.try_recv().ok()),
+                false => match rx.recv_timeout(timeout) {
+                    Ok(message) => Poll::Ready(Some(message)),
+                    Err(RecvTimeoutError::Timeout) => Poll::Pending,
+                    Err(RecvTimeoutError::Disconnected) => Poll::Ready(None),
+                },
+            }
+        });
+        if let NextEntry::Finished = next {
+            self.finish();
+        }
+        next
+    }
+
+    fn enqueue(&mut self, parent: NodeId, path: PathBuf, depth: usize) {
+        self.shared.push_task((parent, path, depth));
     }
 
     fn errors(&self) -> &[Self::Error] {
-        &self.errors
+        self.received.errors()
+    }
+
+    fn counters(&self) -> &Counters {
+        self.shared.scan.counters()
+    }
+
+    fn options(&self) -> &Options {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut Options {
+        Arc::make_mut(&mut self.options)
+    }
+
+    fn estimated_remaining(&self) -> Option<usize> {
+        let pending_dirs = self.shared.pending.load(Relaxed);
+        let counters = self.counters();
+        let buffered = counters
+            .entries_read()
+            .saturating_sub(self.received.consumed());
+        Some(buffered + counters.estimate(pending_dirs))
+    }
+
+    fn pending_dirs(&self) -> Option<usize> {
+        Some(self.shared.pending.load(Relaxed))
+    }
+
+    fn forget_links(&mut self, links: &[Inode]) {
+        self.shared.scan.forget_links(links);
+    }
+
+    fn read_dir_now(&mut self, parent: NodeId, path: &Path, depth: usize) -> Option<Vec<Entry>> {
+        let errors = self.received.errors_mut();
+        let scan = &self.shared.scan;
+        Some(util::read_dir_now(
+            &self.options,
+            scan,
+            parent,
+            depth,
+            path,
+            errors,
+        ))
     }
 }
 
 fn run_thread(
-    threads: &AtomicUsize,
-    running: &AtomicBool,
-    tasks: &TaskHandle,
-    entries: &mpsc::Sender<Result<Entry>>,
+    shared: &Shared,
+    local: &Worker<Task>,
+    stealers: &[Stealer<Task>],
+    options: &Options,
+    entries: &mpsc::SyncSender<Message>,
+    batch_size: usize,
 ) {
-    while running.load(Relaxed) {
-        let Some((parent, path)) = tasks.lock().unwrap().pop_front() else {
-            if threads.load(Relaxed) == 0 {
-                break;
-            }
-            thread::yield_now();
+    while shared.running.load(Relaxed) && !options.cancel.is_cancelled() {
+        let Some((parent, path, depth)) = shared.find_task(local, stealers) else {
+            shared.sleep();
             continue;
         };
 
-        threads.fetch_add(1, Relaxed);
-        util::read_dir(
+        let send = |message| entries.send(message).unwrap();
+        util::read_dir_batched(
+            options,
+            &shared.scan,
             parent,
+            depth,
             &path,
-            |e| entries.send(Ok(e)).unwrap(),
-            |e| entries.send(Err(e)).unwrap(),
+            batch_size,
+            send,
+        );
+        // only after every entry is sent, so none are missed once idle
+        if shared.pending.fetch_sub(1, SeqCst) == 1 {
+            // wakes the source if it's waiting, or else it isn't needed
+            let _ = entries.try_send(Message::default());
+        }
+    }
+    // only after the last send, so the source stops draining once every worker has
+    shared.live.fetch_sub(1, SeqCst);
+    let _ = entries.try_send(Message::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        du::Du,
+        util::test::{scan, tree},
+    };
+
+    #[test]
+    fn short_reads_find_every_entry() {
+        let dir = tree("mt-short-reads", 40, 20);
+        let expected = scan(dir.path(), |_| {});
+
+        let mut du = Du::new(Source::default());
+        du.begin(dir.path());
+        let deadline = Instant::now() + Duration::from_secs(30);
+        // short reads often find the channel empty while workers are still busy
+        while du.progress().entries < expected.progress().entries {
+            assert!(Instant::now() < deadline, "scan never finished");
+            du.read_for(Duration::from_micros(50));
+        }
+        // and once finished, reading again finds nothing more
+        assert_eq!(du.read_for(Duration::from_millis(10)).0, 0);
+        assert_eq!(
+            du.stats().head().info().size,
+            expected.stats().head().info().size
         );
-        threads.fetch_sub(1, Relaxed);
+    }
+
+    #[test]
+    fn finish_joins_workers_stopped_early() {
+        let dir = tree("mt-finish-early", 40, 20);
+        let expected = scan(dir.path(), |_| {});
+
+        // a small buffer leaves the workers waiting on it once it's full
+        let mut du = Du::new(Source::with_batch_size(16, 4));
+        du.begin(dir.path());
+        let mut seen = 0;
+        let read = du.read(&mut |_, _| {
+            seen += 1;
+            seen <= 10
+        });
+        assert_eq!(read, 10);
+
+        let source = &mut du.1;
+        assert!(source.pending_dirs().is_some_and(|n| n > 0));
+        source.finish();
+        assert!(source.workers.is_empty());
+        assert_eq!(source.shared.live.load(SeqCst), 0);
+        // finishing twice is harmless
+        source.finish();
+
+        du.reset();
+        du.begin(dir.path());
+        du.read(&mut |_, _| true);
+        assert_eq!(du.progress().entries, expected.progress().entries);
+        assert_eq!(
+            du.stats().head().info().size,
+            expected.stats().head().info().size
+        );
+    }
+
+    #[test]
+    fn full_buffer_holds_workers_back() {
+        let dir = tree("mt-backpressure", 40, 20);
+        let expected = scan(dir.path(), |_| {});
+
+        let (bound, batch_size) = (8, 4);
+        let mut du = Du::new(Source::with_batch_size(bound, batch_size));
+        du.begin(dir.path());
+        // besides the buffer, each worker may hold a batch it's waiting to send
+        // and another it's still filling, while the source holds the batch
+        // it's taking entries from
+        let workers = du.1.workers.len();
+        let most = bound + (2 * workers + 1) * batch_size;
+        du.read(&mut |stats, source| {
+            let ahead = source.counters().entries_read() - stats.progress().entries;
+            assert!(ahead <= most, "{ahead} entries read ahead");
+            true
+        });
+        assert_eq!(du.progress().entries, expected.progress().entries);
+    }
+
+    #[test]
+    fn zero_bound_still_scans_and_stops() {
+        let dir = tree("mt-zero-bound", 20, 10);
+        let expected = scan(dir.path(), |_| {});
+
+        let mut du = Du::new(Source::new(0));
+        du.begin(dir.path());
+        du.read(&mut |_, _| true);
+        assert_eq!(du.progress().entries, expected.progress().entries);
+
+        du.reset();
+        du.begin(dir.path());
+        let mut seen = 0;
+        du.read(&mut |_, _| {
+            seen += 1;
+            seen <= 3
+        });
+        // returns only once every worker has stopped
+        du.1.finish();
+        assert_eq!(du.1.shared.live.load(SeqCst), 0);
     }
 }
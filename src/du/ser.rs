@@ -0,0 +1,219 @@
+//! serde support, behind the `serde` feature
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+use std::ffi::OsStr;
+
+use super::{Categories, Info, Node, NodeId, Stats};
+
+/// Serialized as its index, so the head is `0`
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.get() as u64)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = usize::deserialize(deserializer)?;
+        if id == usize::MAX {
+            return Err(D::Error::custom("node id out of range"));
+        }
+        Ok(Self::new(id))
+    }
+}
+
+/// Written as its nodes, with each name put back into its node's info
+impl Serialize for Stats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct RawStats<'a> {
+            nodes: Nodes<'a>,
+        }
+
+        RawStats { nodes: Nodes(self) }.serialize(serializer)
+    }
+}
+
+struct Nodes<'a>(&'a Stats);
+
+impl Serialize for Nodes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let stats = self.0;
+        serializer.collect_seq((0..stats.nodes.len()).map(|i| {
+            let id = NodeId::new(i);
+            let node = &stats[id];
+            RawNode {
+                info: NamedInfo {
+                    name: stats.name(id),
+                    info: &node.info,
+                },
+                parent: node.parent,
+                children: &node.children,
+                folded: node.folded.as_deref(),
+                categories: node.categories.as_deref(),
+            }
+        }))
+    }
+}
+
+/// A [`Node`] as written, in the same shape it's read in
+#[derive(Serialize)]
+struct RawNode<'a> {
+    info: NamedInfo<'a>,
+    parent: NodeId,
+    children: &'a [NodeId],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    folded: Option<&'a Info>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    categories: Option<&'a Categories>,
+}
+
+/// An [`Info`] along with its node's name, which it no longer holds
+#[derive(Serialize)]
+struct NamedInfo<'a> {
+    #[serde(serialize_with = "name::serialize")]
+    name: &'a OsStr,
+    #[serde(flatten)]
+    info: &'a Info,
+}
+
+/// [`Stats`] as read, before its tree has been checked
+#[derive(Deserialize)]
+pub(super) struct RawStats {
+    nodes: Vec<Node>,
+}
+
+impl TryFrom<RawStats> for Stats {
+    type Error = String;
+
+    fn try_from(raw: RawStats) -> Result<Self, Self::Error> {
+        Stats::from_nodes(raw.nodes)
+    }
+}
+
+/// Names are strings when valid UTF-8, and raw bytes otherwise
+///
+/// Bytes are only written on Unix; elsewhere invalid names are made lossy.
+pub(super) mod name {
+    use std::{ffi::OsStr, fmt};
+
+    use serde::{
+        Deserializer, Serializer,
+        de::{self, SeqAccess, Visitor},
+    };
+
+    pub fn serialize<S: Serializer>(name: &OsStr, serializer: S) -> Result<S::Ok, S::Error> {
+        match name.to_str() {
+            Some(name) => serializer.serialize_str(name),
+            #[cfg(unix)]
+            None => {
+                use std::os::unix::ffi::OsStrExt;
+                serializer.serialize_bytes(name.as_bytes())
+            }
+            #[cfg(not(unix))]
+            None => serializer.serialize_str(&name.to_string_lossy()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<OsStr>, D::Error> {
+        deserializer.deserialize_any(NameVisitor)
+    }
+
+    struct NameVisitor;
+
+    impl<'de> Visitor<'de> for NameVisitor {
+        type Value = Box<OsStr>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(OsStr::new(v).into())
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            #[cfg(unix)]
+            {
+                use std::os::unix::ffi::OsStrExt;
+                Ok(OsStr::from_bytes(v).into())
+            }
+            #[cfg(not(unix))]
+            Ok(OsStr::new(&*String::from_utf8_lossy(v)).into())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            self.visit_bytes(&bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        du::{NodeId, SizeMode, Stats},
+        util::test::{TempDir, scan},
+    };
+
+    #[test]
+    fn stats_round_trip_through_json() {
+        let dir = TempDir::new("ser-round-trip");
+        dir.file("a/x", 10);
+        dir.file("a/y", 20);
+        dir.file("b", 30);
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"not \xffutf-8");
+            std::fs::write(dir.path().join("a").join(name), [0; 40]).unwrap();
+        }
+        let du = scan(dir.path(), |_| {});
+        let stats = du.stats();
+
+        let json = serde_json::to_string(stats).unwrap();
+        let read: Stats = serde_json::from_str(&json).unwrap();
+        let nodes = |stats: &Stats| -> Vec<_> {
+            (stats.dfs(NodeId::ROOT))
+                .map(|(id, node)| {
+                    let info = node.info();
+                    let size = stats.size(id, SizeMode::Apparent);
+                    (
+                        stats.name(id).to_owned(),
+                        info.kind,
+                        size,
+                        info.files,
+                        info.mtime,
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(nodes(&read), nodes(stats));
+        #[cfg(unix)]
+        assert!(
+            read.dfs(NodeId::ROOT)
+                .any(|(id, _)| read.name(id).to_str().is_none())
+        );
+    }
+
+    #[test]
+    fn malformed_trees_are_rejected() {
+        let dir = TempDir::new("ser-malformed");
+        dir.file("a", 10);
+        let du = scan(dir.path(), |_| {});
+        let json = serde_json::to_value(du.stats()).unwrap();
+
+        let mut orphan = json.clone();
+        orphan["nodes"][0]["children"] = serde_json::json!([]);
+        let err = serde_json::from_value::<Stats>(orphan).unwrap_err();
+        assert!(err.to_string().contains("has no parent"), "{err}");
+
+        let mut out_of_range = json;
+        out_of_range["nodes"][1]["parent"] = serde_json::json!(7);
+        assert!(serde_json::from_value::<Stats>(out_of_range).is_err());
+    }
+}
@@ -0,0 +1,524 @@
+//! a compact binary format for saving [`Stats`] and reading them back
+//!
+//! After the magic bytes and version, a snapshot is a table of the distinct
+//! names followed by a table of nodes, which refer to names by their index.
+//! Every integer is a LEB128 varint. Only the sizes of each entry itself are
+//! written, with the totals being recomputed when read.
+//!
+//! Readers and writers should be buffered, as each value is read or written
+//! separately.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::{self, ErrorKind, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{Categories, Category, FileKind, Info, Inode, Name, Node, NodeId, Owner, Stats, Usage};
+
+const MAGIC: &[u8; 6] = b"dustat";
+/// the only version written or read
+const VERSION: u64 = 1;
+
+const HAS_INODE: u8 = 1 << 0;
+const DUPLICATE: u8 = 1 << 1;
+const HAS_FOLDED: u8 = 1 << 2;
+const OLD: u8 = 1 << 3;
+const HAS_OWNER: u8 = 1 << 4;
+const HAS_CATEGORY: u8 = 1 << 5;
+
+/// The most to reserve up front, so a corrupt count can't exhaust memory
+const MAX_RESERVE: usize = 1 << 16;
+
+impl Stats {
+    /// Saves the stats as a versioned binary snapshot, see [`Stats::read_from`]
+    ///
+    /// Names which aren't valid UTF-8 are written as their raw bytes on Unix,
+    /// and made lossy elsewhere.
+    pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        write_uint(&mut w, VERSION)?;
+
+        let mut names = HashMap::new();
+        let mut table = Vec::new();
+        for i in 0..self.nodes.len() {
+            let name = self.name(NodeId::new(i));
+            names.entry(name).or_insert_with(|| {
+                table.push(name);
+                table.len() - 1
+            });
+        }
+        write_uint(&mut w, table.len() as u64)?;
+        for name in table {
+            write_bytes(&mut w, name)?;
+        }
+
+        write_uint(&mut w, self.nodes.len() as u64)?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            let info = &node.info;
+            write_uint(&mut w, names[self.name(NodeId::new(i))] as u64)?;
+            w.write_all(&[kind_to_byte(info.kind)])?;
+
+            let mut flags = 0;
+            flags |= if info.inode.is_some() { HAS_INODE } else { 0 };
+            flags |= if info.duplicate { DUPLICATE } else { 0 };
+            flags |= if node.folded.is_some() { HAS_FOLDED } else { 0 };
+            flags |= if info.old { OLD } else { 0 };
+            flags |= if info.owner.is_some() { HAS_OWNER } else { 0 };
+            flags |= if info.category.is_some() {
+                HAS_CATEGORY
+            } else {
+                0
+            };
+            w.write_all(&[flags])?;
+
+            write_uint(&mut w, info.own)?;
+            write_uint(&mut w, info.own_alloc)?;
+            if let Some(inode) = info.inode {
+                write_uint(&mut w, inode.dev)?;
+                write_uint(&mut w, inode.ino)?;
+                write_uint(&mut w, inode.nlink)?;
+            }
+            write_time(&mut w, info.mtime)?;
+            write_time(&mut w, info.atime)?;
+            if let Some(owner) = info.owner {
+                write_uint(&mut w, owner.uid.into())?;
+                write_uint(&mut w, owner.gid.into())?;
+            }
+            if let Some(category) = &info.category {
+                write_str(&mut w, category.name())?;
+            }
+            if let Some(folded) = &node.folded {
+                for n in [
+                    folded.size,
+                    folded.alloc,
+                    folded.files,
+                    folded.dirs,
+                    folded.other,
+                    folded.duplicates,
+                    folded.old_size,
+                ] {
+                    write_uint(&mut w, n)?;
+                }
+                write_time(&mut w, folded.newest)?;
+
+                let categories = node.categories.as_ref().map_or(&[][..], |c| &c.folded);
+                write_uint(&mut w, categories.len() as u64)?;
+                for (category, usage) in categories {
+                    write_str(&mut w, category.name())?;
+                    write_uint(&mut w, usage.size)?;
+                    write_uint(&mut w, usage.alloc)?;
+                    write_uint(&mut w, usage.entries)?;
+                }
+            }
+
+            write_uint(&mut w, node.parent.get() as u64)?;
+            write_uint(&mut w, node.children.len() as u64)?;
+            for child in &node.children {
+                write_uint(&mut w, child.get() as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads stats saved by [`Stats::write_to`]
+    ///
+    /// Fails with [`ErrorKind::InvalidData`] if the snapshot is malformed,
+    /// or from another version.
+    pub fn read_from(mut r: impl Read) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a dustat snapshot"));
+        }
+        let version = read_uint(&mut r)?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported snapshot version {version}")));
+        }
+
+        let count = read_len(&mut r)?;
+        let mut names = Vec::with_capacity(count.min(MAX_RESERVE));
+        for _ in 0..count {
+            names.push(read_bytes(&mut r)?);
+        }
+
+        let count = read_len(&mut r)?;
+        let mut nodes = Vec::with_capacity(count.min(MAX_RESERVE));
+        for _ in 0..count {
+            let name = read_len(&mut r)?;
+            let name = names
+                .get(name)
+                .ok_or_else(|| invalid("name out of range"))?;
+            let [kind, flags] = read_array(&mut r)?;
+            let kind = kind_from_byte(kind)?;
+
+            let mut info = Info::new(name.as_os_str(), kind, 0);
+            info.own = read_uint(&mut r)?;
+            info.own_alloc = read_uint(&mut r)?;
+            if flags & HAS_INODE != 0 {
+                info.inode = Some(Inode {
+                    dev: read_uint(&mut r)?,
+                    ino: read_uint(&mut r)?,
+                    nlink: read_uint(&mut r)?,
+                });
+            }
+            info.duplicate = flags & DUPLICATE != 0;
+            info.old = flags & OLD != 0;
+            info.mtime = read_time(&mut r)?;
+            info.atime = read_time(&mut r)?;
+            if flags & HAS_OWNER != 0 {
+                info.owner = Some(Owner {
+                    uid: read_id32(&mut r)?,
+                    gid: read_id32(&mut r)?,
+                });
+            }
+            if flags & HAS_CATEGORY != 0 {
+                info.category = Some(Category::new(read_str(&mut r)?));
+            }
+            let folded = match flags & HAS_FOLDED != 0 {
+                true => Some(Box::new(Info {
+                    size: read_uint(&mut r)?,
+                    alloc: read_uint(&mut r)?,
+                    files: read_uint(&mut r)?,
+                    dirs: read_uint(&mut r)?,
+                    other: read_uint(&mut r)?,
+                    duplicates: read_uint(&mut r)?,
+                    old_size: read_uint(&mut r)?,
+                    newest: read_time(&mut r)?,
+                    ..Info::default()
+                })),
+                false => None,
+            };
+            let mut categories = None;
+            if flags & HAS_FOLDED != 0 {
+                let len = read_len(&mut r)?;
+                let mut folded = Vec::with_capacity(len.min(MAX_RESERVE));
+                for _ in 0..len {
+                    let category = Category::new(read_str(&mut r)?);
+                    let usage = Usage {
+                        size: read_uint(&mut r)?,
+                        alloc: read_uint(&mut r)?,
+                        entries: read_uint(&mut r)?,
+                    };
+                    folded.push((category, usage));
+                }
+                if !folded.is_empty() {
+                    categories = Some(Box::new(Categories {
+                        total: Vec::new(),
+                        folded,
+                    }));
+                }
+            }
+
+            let parent = read_id(&mut r)?;
+            let len = read_len(&mut r)?;
+            let mut children = Vec::with_capacity(len.min(MAX_RESERVE));
+            for _ in 0..len {
+                children.push(read_id(&mut r)?);
+            }
+
+            nodes.push(Node {
+                info,
+                name: Name::default(),
+                parent,
+                children,
+                folded,
+                categories,
+            });
+        }
+
+        let mut stats = Self::from_nodes(nodes).map_err(invalid)?;
+        stats.recompute_aggregates();
+        Ok(stats)
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+fn kind_to_byte(kind: FileKind) -> u8 {
+    match kind {
+        FileKind::Dir => 0,
+        FileKind::File => 1,
+        FileKind::Symlink => 2,
+        FileKind::Other => 3,
+    }
+}
+
+fn kind_from_byte(b: u8) -> io::Result<FileKind> {
+    Ok(match b {
+        0 => FileKind::Dir,
+        1 => FileKind::File,
+        2 => FileKind::Symlink,
+        3 => FileKind::Other,
+        _ => return Err(invalid(format!("unknown file kind {b}"))),
+    })
+}
+
+fn write_uint(w: &mut impl Write, mut n: u64) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        buf[len] = byte | if n == 0 { 0 } else { 0x80 };
+        len += 1;
+        if n == 0 {
+            break;
+        }
+    }
+    w.write_all(&buf[..len])
+}
+
+fn read_uint(r: &mut impl Read) -> io::Result<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let [byte] = read_array(r)?;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("integer too long"))
+}
+
+/// Writes a time as a flag for whether it's known, then the seconds and
+/// nanoseconds since the Unix epoch, with the seconds zigzag encoded
+fn write_time(w: &mut impl Write, time: Option<SystemTime>) -> io::Result<()> {
+    let Some(time) = time else {
+        return w.write_all(&[0]);
+    };
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        // before the epoch, counted down to a whole second then back up
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+            }
+        }
+    };
+    w.write_all(&[1])?;
+    write_uint(w, ((secs << 1) ^ (secs >> 63)) as u64)?;
+    write_uint(w, nanos.into())
+}
+
+fn read_time(r: &mut impl Read) -> io::Result<Option<SystemTime>> {
+    let [known] = read_array(r)?;
+    if known == 0 {
+        return Ok(None);
+    }
+    let zigzag = read_uint(r)?;
+    let secs = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+    let nanos = u32::try_from(read_uint(r)?)
+        .ok()
+        .filter(|&n| n < 1_000_000_000)
+        .ok_or_else(|| invalid("nanoseconds out of range"))?;
+
+    let time = match secs >= 0 {
+        true => UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64)),
+        false => UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())),
+    };
+    (time.and_then(|t| t.checked_add(Duration::from_nanos(nanos.into()))))
+        .map(Some)
+        .ok_or_else(|| invalid("time out of range"))
+}
+
+fn read_len(r: &mut impl Read) -> io::Result<usize> {
+    usize::try_from(read_uint(r)?).map_err(|_| invalid("length out of range"))
+}
+
+fn read_id(r: &mut impl Read) -> io::Result<NodeId> {
+    match read_len(r)? {
+        usize::MAX => Err(invalid("node id out of range")),
+        id => Ok(NodeId::new(id)),
+    }
+}
+
+fn read_id32(r: &mut impl Read) -> io::Result<u32> {
+    u32::try_from(read_uint(r)?).map_err(|_| invalid("owner out of range"))
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn write_bytes(w: &mut impl Write, name: &OsStr) -> io::Result<()> {
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(name);
+    #[cfg(not(unix))]
+    let lossy = name.to_string_lossy();
+    #[cfg(not(unix))]
+    let bytes = lossy.as_bytes();
+
+    write_uint(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn write_str(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write_uint(w, s.len() as u64)?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_str(r: &mut impl Read) -> io::Result<String> {
+    (read_bytes(r)?.into_string()).map_err(|_| invalid("category isn't valid UTF-8"))
+}
+
+fn read_bytes(r: &mut impl Read) -> io::Result<OsString> {
+    let len = read_len(r)?;
+    let mut bytes = Vec::with_capacity(len.min(MAX_RESERVE));
+    r.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    #[cfg(unix)]
+    return Ok(std::os::unix::ffi::OsStringExt::from_vec(bytes));
+    #[cfg(not(unix))]
+    return Ok(String::from_utf8_lossy(&bytes).into_owned().into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every field of each node that's written, along with the totals found from them
+    fn summary(stats: &Stats) -> Vec<String> {
+        (stats.dfs(NodeId::ROOT))
+            .map(|(id, node)| {
+                let info = &node.info;
+                format!(
+                    "{:?} {:?} own={} {} inode={:?} mtime={:?} atime={:?} owner={:?} {:?} \
+                     dup={} old={} size={} alloc={} files={} other={} folded={:?} {:?}",
+                    stats.name(id),
+                    info.kind,
+                    info.own,
+                    info.own_alloc,
+                    info.inode,
+                    info.mtime,
+                    info.atime,
+                    info.owner,
+                    info.category,
+                    info.duplicate,
+                    info.old,
+                    info.size,
+                    info.alloc,
+                    info.files,
+                    info.other,
+                    node.folded,
+                    stats.categories(id),
+                )
+            })
+            .collect()
+    }
+
+    fn write(stats: &Stats) -> Vec<u8> {
+        let mut buf = Vec::new();
+        stats.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let mut stats = Stats::new();
+        stats.set_name(NodeId::ROOT, OsStr::new("/scan"));
+
+        let mut dir = Info::new(OsStr::new("dir"), FileKind::Dir, 4096);
+        dir.inode = Some(Inode {
+            dev: u64::MAX,
+            ino: 7,
+            nlink: 2,
+        });
+        dir.owner = Some(Owner {
+            uid: u32::MAX,
+            gid: 0,
+        });
+        // before the epoch, and not by a whole second
+        dir.mtime = UNIX_EPOCH.checked_sub(Duration::from_millis(1500));
+        dir.atime = Some(UNIX_EPOCH + Duration::new(1_700_000_000, 999_999_999));
+        dir.old = true;
+        let dir = stats.push(NodeId::ROOT, dir);
+
+        #[cfg(unix)]
+        let name = std::os::unix::ffi::OsStrExt::from_bytes(b"not \xffutf-8");
+        #[cfg(not(unix))]
+        let name = OsStr::new("huge");
+        let mut huge = Info::new(name, FileKind::File, u64::MAX);
+        huge.category = Some(Category::new("video"));
+        huge.mtime = None;
+        stats.push(dir, huge);
+
+        let mut link = Info::new(OsStr::new("link"), FileKind::File, 10);
+        link.inode = Some(Inode {
+            dev: 1,
+            ino: 2,
+            nlink: 3,
+        });
+        link.set_duplicate();
+        stats.push(dir, link);
+        stats.push(dir, Info::new(OsStr::new("dir"), FileKind::Symlink, 0));
+
+        // a folded entry gives its directory both folded totals and categories
+        let mut deep = Info::new(OsStr::new("deep"), FileKind::File, 100);
+        deep.category = Some(Category::new("text"));
+        deep.newest = UNIX_EPOCH.checked_sub(Duration::from_secs(60));
+        stats.fold(dir, &deep);
+
+        let read = Stats::read_from(&write(&stats)[..]).unwrap();
+        assert_eq!(summary(&read), summary(&stats));
+        // names shared by several nodes are only written once
+        let dir_name = write(&stats).windows(3).filter(|w| w == b"dir").count();
+        assert_eq!(dir_name, 1);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for n in [0, 1, 127, 128, 300, u32::MAX.into(), u64::MAX - 1, u64::MAX] {
+            let mut buf = Vec::new();
+            write_uint(&mut buf, n).unwrap();
+            assert_eq!(read_uint(&mut &buf[..]).unwrap(), n);
+        }
+        // eleven bytes can't be a u64
+        let too_long = [0x80; 11];
+        assert_eq!(
+            read_uint(&mut &too_long[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn bad_snapshots_are_rejected() {
+        let kind = |bytes: &[u8]| Stats::read_from(bytes).unwrap_err().kind();
+        let good = write(&Stats::new());
+        assert!(Stats::read_from(&good[..]).is_ok());
+
+        assert_eq!(kind(b"notdustat"), ErrorKind::InvalidData);
+
+        let mut version = MAGIC.to_vec();
+        write_uint(&mut version, VERSION + 1).unwrap();
+        assert_eq!(kind(&version), ErrorKind::InvalidData);
+
+        for len in 0..good.len() {
+            assert_eq!(kind(&good[..len]), ErrorKind::UnexpectedEof, "cut at {len}");
+        }
+
+        // lengths far past the end are found to be wrong without reserving them
+        let mut names = MAGIC.to_vec();
+        write_uint(&mut names, VERSION).unwrap();
+        write_uint(&mut names, 1 << 60).unwrap();
+        assert_eq!(kind(&names), ErrorKind::UnexpectedEof);
+
+        let mut name = MAGIC.to_vec();
+        write_uint(&mut name, VERSION).unwrap();
+        write_uint(&mut name, 1).unwrap();
+        write_uint(&mut name, u64::MAX >> 1).unwrap();
+        name.extend_from_slice(b"short");
+        assert_eq!(kind(&name), ErrorKind::UnexpectedEof);
+    }
+}
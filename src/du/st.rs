@@ -1,31 +1,59 @@
-use std::{io::Error, path::PathBuf};
+use std::path::{Path, PathBuf};
 
 use crate::{
-    du::{DuSource, Entry, NodeId},
-    util,
+    du::{Counters, DuSource, Entry, FileKind, Inode, NodeId, Options, ScanError},
+    util::{self, ScanState},
 };
 
 #[derive(Default)]
 pub struct Source {
     entries: Vec<Entry>,
-    errors: Vec<Error>,
+    /// directories within `entries`
+    pending_dirs: usize,
+    errors: Vec<ScanError>,
+    scan: ScanState,
+    options: Options,
 }
 
 impl DuSource for Source {
-    type Error = Error;
+    type Error = ScanError;
 
     fn begin(&mut self) {}
     fn finish(&mut self) {}
 
+    fn reset(&mut self) {
+        self.entries.clear();
+        self.pending_dirs = 0;
+        self.errors.clear();
+        self.scan.reset();
+    }
+
     fn next_entry(&mut self) -> Option<Entry> {
-        self.entries.pop()
+        if self.options.cancel.is_cancelled() {
+            self.entries.clear();
+            self.pending_dirs = 0;
+            return None;
+        }
+        let entry = self.entries.pop()?;
+        if entry.info.kind == FileKind::Dir {
+            self.pending_dirs -= 1;
+        }
+        Some(entry)
     }
 
-    fn enqueue(&mut self, parent: NodeId, path: PathBuf) {
+    fn enqueue(&mut self, parent: NodeId, path: PathBuf, depth: usize) {
         util::read_dir(
+            &self.options,
+            &self.scan,
             parent,
+            depth,
             &path,
-            |e| self.entries.push(e),
+            |e| {
+                if e.info.kind == FileKind::Dir {
+                    self.pending_dirs += 1;
+                }
+                self.entries.push(e);
+            },
             |e| self.errors.push(e),
         );
     }
@@ -33,4 +61,40 @@ impl DuSource for Source {
     fn errors(&self) -> &[Self::Error] {
         &self.errors
     }
+
+    fn counters(&self) -> &Counters {
+        self.scan.counters()
+    }
+
+    fn options(&self) -> &Options {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    fn estimated_remaining(&self) -> Option<usize> {
+        Some(self.entries.len() + self.counters().estimate(self.pending_dirs))
+    }
+
+    fn pending_dirs(&self) -> Option<usize> {
+        Some(self.pending_dirs)
+    }
+
+    fn forget_links(&mut self, links: &[Inode]) {
+        self.scan.forget_links(links);
+    }
+
+    fn read_dir_now(&mut self, parent: NodeId, path: &Path, depth: usize) -> Option<Vec<Entry>> {
+        let errors = &mut self.errors;
+        Some(util::read_dir_now(
+            &self.options,
+            &self.scan,
+            parent,
+            depth,
+            path,
+            errors,
+        ))
+    }
 }
@@ -0,0 +1,393 @@
+//! an async source for tokio, behind the `async` feature
+
+use std::{
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{
+            AtomicBool, AtomicUsize,
+            Ordering::{Relaxed, SeqCst},
+        },
+    },
+    task::Poll,
+    time::Duration,
+};
+
+use ::tokio::{
+    runtime::Handle,
+    sync::{
+        Notify,
+        mpsc::{self, error::TryRecvError},
+    },
+};
+
+use crate::{
+    du::{Counters, Du, DuSource, Entry, Inode, NextEntry, NodeId, Options, ScanError, Stats, mt},
+    util::{self, Batches, Message, ScanState},
+};
+
+type Task = (NodeId, PathBuf, usize);
+
+/// State shared between the source and the tasks of a single run
+struct Run {
+    /// tasks spawned but not yet finished
+    pending: AtomicUsize,
+    /// set once the run is over, so tasks which haven't started are skipped
+    stopped: AtomicBool,
+    /// notified whenever a batch is sent or a task finishes
+    notify: Notify,
+    /// set along with `notify`, for the reads which block rather than await
+    woken: Mutex<bool>,
+    wake: Condvar,
+    tx_entries: mpsc::Sender<Message>,
+}
+
+impl Run {
+    fn new(bound: usize) -> (Arc<Self>, mpsc::Receiver<Message>) {
+        // tokio's channels panic given a bound of zero
+        let (tx_entries, rx_entries) = mpsc::channel(bound.max(1));
+        let run = Self {
+            pending: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            notify: Notify::new(),
+            woken: Mutex::new(false),
+            wake: Condvar::new(),
+            tx_entries,
+        };
+        (Arc::new(run), rx_entries)
+    }
+
+    fn send(&self, message: Message) {
+        // fails only once the run was replaced, when nothing is read anymore
+        let _ = self.tx_entries.blocking_send(message);
+        self.wake();
+    }
+
+    /// Wakes the source, whether it's awaiting or blocking on an entry
+    fn wake(&self) {
+        self.notify.notify_one();
+        *self.woken.lock().unwrap() = true;
+        self.wake.notify_all();
+    }
+
+    /// Blocks until woken, or for at most `timeout`
+    fn sleep(&self, timeout: Duration) {
+        let woken = self.woken.lock().unwrap();
+        let (mut woken, _) = (self.wake)
+            .wait_timeout_while(woken, timeout, |woken| !*woken)
+            .unwrap();
+        *woken = false;
+    }
+}
+
+/// Reads directories on tokio's blocking thread pool, for use within a runtime
+///
+/// Each directory is read by its own call to [`spawn_blocking`], with entries
+/// being sent back in batches like [`mt::Source`]. [`Du::read_async`] awaits
+/// entries without blocking the runtime, while the other reads of [`Du`] block
+/// the thread, so must not be used on a runtime's thread.
+///
+/// [`spawn_blocking`]: Handle::spawn_blocking
+pub struct Source {
+    runtime: Handle,
+    options: Arc<Options>,
+    scan: Arc<ScanState>,
+    bound: usize,
+
+    run: Arc<Run>,
+    rx_entries: mpsc::Receiver<Message>,
+    /// tasks enqueued before the scan began
+    queued: Vec<Task>,
+    started: bool,
+
+    /// the batches taken out of `rx_entries`
+    received: Batches,
+}
+
+/// # Panics
+///
+/// If called outside of a tokio runtime, like [`Source::new`]. Use
+/// [`Source::with_runtime`] to read on a runtime given elsewhere.
+impl Default for Source {
+    fn default() -> Self {
+        Self::new(mt::Source::DEFAULT_BOUND)
+    }
+}
+
+impl Source {
+    /// Creates a source which buffers at most `bound` unread entries
+    ///
+    /// Directories are read on the current runtime, so this panics if called
+    /// outside of one. The bound is rounded up to a whole number of batches.
+    pub fn new(bound: usize) -> Self {
+        Self::with_runtime(Handle::current(), bound)
+    }
+
+    /// Creates a source which reads directories on `runtime`
+    pub fn with_runtime(runtime: Handle, bound: usize) -> Self {
+        let bound = bound.div_ceil(mt::Source::DEFAULT_BATCH_SIZE);
+        let (run, rx_entries) = Run::new(bound);
+
+        Self {
+            runtime,
+            options: Arc::default(),
+            scan: Arc::default(),
+            bound,
+            run,
+            rx_entries,
+            queued: Vec::new(),
+            started: false,
+            received: Batches::default(),
+        }
+    }
+
+    /// Waits until an entry may be ready
+    async fn wait(&self) {
+        self.run.notify.notified().await;
+    }
+
+    fn spawn(&self, (parent, path, depth): Task) {
+        let run = self.run.clone();
+        let scan = self.scan.clone();
+        let options = self.options.clone();
+
+        run.pending.fetch_add(1, SeqCst);
+        self.runtime.spawn_blocking(move || {
+            if !run.stopped.load(Relaxed) && !options.cancel.is_cancelled() {
+                read_dir(&run, &scan, &options, parent, depth, &path);
+            }
+            // only after every entry is sent, so none are missed once idle
+            run.pending.fetch_sub(1, SeqCst);
+            run.wake();
+        });
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl DuSource for Source {
+    type Error = ScanError;
+
+    fn begin(&mut self) {
+        self.finish();
+
+        // tasks of the last run may still be going, so they're left to it
+        (self.run, self.rx_entries) = Run::new(self.bound);
+        self.started = true;
+        for task in mem::take(&mut self.queued) {
+            self.spawn(task);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.run.stopped.store(true, Relaxed);
+        self.started = false;
+        // tasks waiting for room to send then fail at once, rather than
+        // holding onto their threads, while what was sent can still be read
+        self.rx_entries.close();
+    }
+
+    fn reset(&mut self) {
+        self.finish();
+        (self.run, self.rx_entries) = Run::new(self.bound);
+        self.queued.clear();
+        self.received.clear();
+
+        match Arc::get_mut(&mut self.scan) {
+            Some(scan) => scan.reset(),
+            None => self.scan = Arc::default(),
+        }
+    }
+
+    fn next_entry(&mut self) -> Option<Entry> {
+        util::next_entry(self)
+    }
+
+    fn poll_entry(&mut self) -> NextEntry {
+        let (run, cancel, rx) = (&self.run, &self.options.cancel, &mut self.rx_entries);
+        let next = self.received.next(|| {
+            // checked first, as everything sent before going idle is then received
+            let idle = run.pending.load(SeqCst) == 0 || cancel.is_cancelled();
+            match rx.try_recv() {
+                Ok(message) => Poll::Ready(Some(message)),
+                Err(TryRecvError::Empty) if !idle => Poll::Pending,
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => Poll::Ready(None),
+            }
+        });
+        if let NextEntry::Finished = next {
+            self.finish();
+        }
+        next
+    }
+
+    fn wait_entry(&mut self, timeout: Duration) -> NextEntry {
+        match self.poll_entry() {
+            NextEntry::Pending => {
+                self.run.sleep(timeout);
+                self.poll_entry()
+            }
+            next => next,
+        }
+    }
+
+    fn enqueue(&mut self, parent: NodeId, path: PathBuf, depth: usize) {
+        let task = (parent, path, depth);
+        match self.started {
+            true => self.spawn(task),
+            false => self.queued.push(task),
+        }
+    }
+
+    fn errors(&self) -> &[Self::Error] {
+        self.received.errors()
+    }
+
+    fn counters(&self) -> &Counters {
+        self.scan.counters()
+    }
+
+    fn options(&self) -> &Options {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut Options {
+        Arc::make_mut(&mut self.options)
+    }
+
+    fn estimated_remaining(&self) -> Option<usize> {
+        let pending_dirs = self.run.pending.load(Relaxed) + self.queued.len();
+        let counters = self.counters();
+        let buffered = counters
+            .entries_read()
+            .saturating_sub(self.received.consumed());
+        Some(buffered + counters.estimate(pending_dirs))
+    }
+
+    fn pending_dirs(&self) -> Option<usize> {
+        Some(self.run.pending.load(Relaxed) + self.queued.len())
+    }
+
+    fn forget_links(&mut self, links: &[Inode]) {
+        self.scan.forget_links(links);
+    }
+
+    fn read_dir_now(&mut self, parent: NodeId, path: &Path, depth: usize) -> Option<Vec<Entry>> {
+        let errors = self.received.errors_mut();
+        Some(util::read_dir_now(
+            &self.options,
+            &self.scan,
+            parent,
+            depth,
+            path,
+            errors,
+        ))
+    }
+}
+
+impl Du<Source> {
+    /// Reads entries until `with` returns false, awaiting them as they arrive
+    ///
+    /// Dropping the future stops the read without losing any entries, so it
+    /// can be raced against a timeout or shutdown signal and resumed later.
+    pub async fn read_async(
+        &mut self,
+        mut with: impl FnMut(&mut Stats, &mut Source) -> bool,
+    ) -> usize {
+        let cancel = self.1.options().cancel.clone();
+        let mut count = 0;
+        // checked before taking an entry, so a stop never drops one
+        while !cancel.is_cancelled() && with(&mut self.0, &mut self.1) {
+            let entry = match self.1.poll_entry() {
+                NextEntry::Ready(entry) => entry,
+                NextEntry::Pending => {
+                    self.1.wait().await;
+                    continue;
+                }
+                NextEntry::Finished => {
+                    self.finish_totals();
+                    break;
+                }
+            };
+            self.add(entry);
+            count += 1;
+            // entries may keep arriving, so the runtime is given a turn now and then
+            if count % mt::Source::DEFAULT_BATCH_SIZE == 0 {
+                ::tokio::task::yield_now().await;
+            }
+        }
+
+        if cancel.is_cancelled() {
+            self.1.finish();
+        }
+        count
+    }
+}
+
+fn read_dir(
+    run: &Run,
+    scan: &ScanState,
+    options: &Options,
+    parent: NodeId,
+    depth: usize,
+    path: &Path,
+) {
+    let batch_size = mt::Source::DEFAULT_BATCH_SIZE;
+    util::read_dir_batched(options, scan, parent, depth, path, batch_size, |message| {
+        run.send(message);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use ::tokio::runtime::Builder;
+
+    use super::*;
+    use crate::util::test::{scan, tree};
+
+    #[test]
+    fn finish_releases_tasks_waiting_to_send() {
+        let dir = tree("tokio-finish", 40, 20);
+        let expected = scan(dir.path(), |_| {});
+        let runtime = Builder::new_current_thread()
+            .max_blocking_threads(2)
+            .build()
+            .unwrap();
+
+        let mut du = Du::new(Source::with_runtime(runtime.handle().clone(), 1));
+        du.begin(dir.path());
+        let mut seen = 0;
+        du.read(&mut |_, _| {
+            seen += 1;
+            seen <= 3
+        });
+        let run = du.1.run.clone();
+        // two more directories being read, with room for only one's entries,
+        // leaves the other waiting to send them
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while du.1.counters().dirs_read() < 3 {
+            assert!(Instant::now() < deadline, "tasks never began");
+            run.sleep(Duration::from_millis(10));
+        }
+        du.1.finish();
+
+        // with only two threads, the rest of the tasks only run once the
+        // one stuck on the full buffer gives up
+        while run.pending.load(SeqCst) > 0 {
+            assert!(Instant::now() < deadline, "tasks never finished");
+            run.sleep(Duration::from_millis(10));
+        }
+
+        // and the source is left ready for another scan
+        du.reset();
+        du.begin(dir.path());
+        du.read(&mut |_, _| true);
+        assert_eq!(du.progress().entries, expected.progress().entries);
+    }
+}
@@ -0,0 +1,525 @@
+//! following changes to the filesystem after a scan
+//!
+//! A [`Watcher`] is made by [`Du::watch`] once a scan is finished, with each
+//! later call to [`Du::update`] reading only the directories which changed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    du::{Du, DuSource, FileKind, NodeId, ScanError, ScanOp, Stats},
+    util,
+};
+
+/// Follows changes to the directories of a scan, see [`Du::update`]
+///
+/// On Linux, changes are found through inotify, so they're seen as they
+/// happen. Elsewhere, each directory is polled on every update, both for a
+/// new modification time and for changes to its files' sizes or modification
+/// times. Polling lists every watched directory again, so an update costs
+/// about as many reads as the scan did, though its totals aren't rebuilt.
+pub struct Watcher {
+    sys: sys::Watcher,
+    /// whether to skip directories on another device than their parent
+    one_filesystem: bool,
+    errors: Vec<ScanError>,
+}
+
+impl Watcher {
+    /// Directories which couldn't be watched, so won't be updated
+    ///
+    /// On Linux, this is usually from running out of inotify watches, whose
+    /// limit is set by `fs.inotify.max_user_watches`.
+    pub fn errors(&self) -> &[ScanError] {
+        &self.errors
+    }
+
+    /// Watches every directory read within `from`, including itself
+    fn watch(&mut self, stats: &Stats, from: NodeId) {
+        // the devices of the directories watched, for those in `from` to be compared with
+        let mut devs = HashMap::new();
+        if self.one_filesystem && from != NodeId::ROOT {
+            let parent = stats[from].parent;
+            devs.insert(parent, dev_of(stats, parent, &stats.path_of(parent)));
+        }
+
+        for (id, node) in stats.dfs(from) {
+            // the head of `begin` stands for the directory read, without being one
+            let is_dir = match id == NodeId::ROOT {
+                true => !stats.name(NodeId::ROOT).is_empty(),
+                false => node.info.kind == FileKind::Dir,
+            };
+            if !is_dir {
+                continue;
+            }
+
+            let path = stats.path_of(id);
+            if self.one_filesystem {
+                let dev = dev_of(stats, id, &path);
+                let parent = devs.get(&node.parent).copied().flatten();
+                devs.insert(id, dev);
+                // never read by the scan, being on another device than its parent
+                if id != NodeId::ROOT && dev.zip(parent).is_some_and(|(a, b)| a != b) {
+                    continue;
+                }
+            }
+
+            if let Err(e) = self.sys.add(&path) {
+                self.errors.push(ScanError::new(path, ScanOp::Watch, id, e));
+                continue;
+            }
+            if node.folded.is_some() {
+                self.watch_folded(id, &path, devs.get(&id).copied().flatten());
+            }
+        }
+    }
+
+    /// Watches the directories folded into `id`, which have no nodes to find them by
+    fn watch_folded(&mut self, id: NodeId, path: &Path, dev: Option<u64>) {
+        let mut stack = vec![path.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            let Ok(read) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for de in read.flatten() {
+                if !de.file_type().is_ok_and(|t| t.is_dir()) {
+                    continue;
+                }
+                let path = de.path();
+                if let Some(dev) = dev.filter(|_| self.one_filesystem) {
+                    let inode = (de.metadata().ok().as_ref()).and_then(util::get_inode);
+                    if util::get_dev(&path, inode).is_some_and(|d| d != dev) {
+                        continue;
+                    }
+                }
+                match self.sys.add(&path) {
+                    Ok(()) => stack.push(path),
+                    Err(e) => self.errors.push(ScanError::new(path, ScanOp::Watch, id, e)),
+                }
+            }
+        }
+    }
+}
+
+/// The device `id` is on, where the head's info has to be read again
+fn dev_of(stats: &Stats, id: NodeId, path: &Path) -> Option<u64> {
+    let inode = match id == NodeId::ROOT {
+        true => (std::fs::metadata(path).ok().as_ref()).and_then(util::get_inode),
+        false => stats[id].info.inode,
+    };
+    util::get_dev(path, inode)
+}
+
+impl<P: DuSource> Du<P> {
+    /// Starts watching the directories read, for [`Du::update`] to apply changes to
+    ///
+    /// Fails only if the platform's watcher can't be made, where a directory
+    /// which can't be watched is instead listed in [`Watcher::errors`].
+    ///
+    /// Only Linux has a watcher of its own, through inotify. Elsewhere, neither
+    /// `ReadDirectoryChangesW` on Windows nor FSEvents on macOS are used yet,
+    /// so every [`Du::update`] polls each watched directory, listing them all
+    /// again. That costs time in the size of the whole tree on every update,
+    /// however little changed.
+    pub fn watch(&self) -> io::Result<Watcher> {
+        let mut watcher = Watcher {
+            sys: sys::Watcher::new()?,
+            one_filesystem: self.1.options().one_filesystem,
+            errors: Vec::new(),
+        };
+        watcher.watch(&self.0, NodeId::ROOT);
+        Ok(watcher)
+    }
+
+    /// Applies the changes seen by `watcher` since the last update
+    ///
+    /// Each directory changed is read again on its own, with new entries
+    /// added, missing ones removed, and the rest updated in place. New
+    /// directories are read in full, then watched as well. If changes were
+    /// lost, such as from inotify's queue overflowing, everything is rescanned.
+    ///
+    /// Entries folded by [`Du::aggregate_depth`] have no nodes to update, so
+    /// a change within them has their ancestor with a node read in full.
+    ///
+    /// Like [`Du::rescan`], this renumbers nodes, so must only be called
+    /// between scans. Returns the number of directories read again.
+    pub fn update(&mut self, watcher: &mut Watcher) -> usize {
+        let mut changed = HashSet::new();
+        if watcher.sys.changes(&mut changed) {
+            self.rescan(NodeId::ROOT);
+            self.read(&mut |_, _| true);
+            watcher.watch(&self.0, NodeId::ROOT);
+            return 1;
+        }
+
+        // parents first, so a directory removed along with its parent is skipped
+        let mut changed: Vec<_> = changed.into_iter().collect();
+        changed.sort_by_key(|path| path.components().count());
+
+        let mut updated = 0;
+        for path in changed {
+            let mut dirs = Vec::new();
+            match self.0.find(&path) {
+                Some(id) => updated += self.update_dir(id, &mut dirs) as usize,
+                None => updated += self.update_folded(&path, &mut dirs) as usize,
+            }
+            if dirs.is_empty() {
+                continue;
+            }
+
+            // read before the next update, which may renumber the nodes queued
+            self.1.begin();
+            self.read(&mut |_, _| true);
+            for dir in dirs {
+                if let Some(id) = self.0.find(&dir) {
+                    watcher.watch(&self.0, id);
+                }
+            }
+        }
+        updated
+    }
+
+    /// Reads the directory of `id` again, adding the paths of any new directories to `dirs`
+    fn update_dir(&mut self, id: NodeId, dirs: &mut Vec<PathBuf>) -> bool {
+        let depth = self.depth_of(id);
+        let options = self.1.options();
+        if options.max_depth.is_some_and(|max| depth > max) {
+            return false;
+        }
+        // its entries have no nodes to compare, so it's read in full
+        if options.aggregate_depth.is_some_and(|max| depth > max) {
+            self.requeue(id, depth);
+            dirs.push(self.0.path_of(id));
+            return true;
+        }
+
+        let links: Vec<_> = (self.0[id].children.iter())
+            .map(|&c| &self.0[c].info)
+            .filter(|info| info.kind != FileKind::Dir && !info.duplicate)
+            .filter_map(|info| info.inode.filter(|inode| inode.nlink > 1))
+            .collect();
+        self.1.forget_links(&links);
+
+        let path = self.0.path_of(id);
+        let Some(entries) = self.1.read_dir_now(id, &path, depth) else {
+            self.requeue(id, depth);
+            dirs.push(path);
+            return true;
+        };
+        let info = self.restat(id, &path);
+        self.0.set_info(id, info);
+
+        // entries which are gone, or now of another kind, are dropped before any are added
+        let kinds: HashMap<&OsStr, FileKind> = (entries.iter())
+            .map(|e| (&*e.info.name, e.info.kind))
+            .collect();
+        let gone: Vec<_> = (self.0[id].children.iter().copied())
+            .filter(|&c| kinds.get(self.0.name(c)) != Some(&self.0[c].info.kind))
+            .collect();
+        self.0.prune(&gone);
+
+        let kept: HashMap<Box<OsStr>, NodeId> = (self.0[id].children.iter())
+            .map(|&c| (self.0.name(c).into(), c))
+            .collect();
+        for entry in entries {
+            match kept.get(&entry.info.name) {
+                // directories keep their contents, with only their own info changing
+                Some(&c) => {
+                    self.0.set_info(c, entry.info);
+                    self.0.retotal(c);
+                }
+                None => {
+                    if entry.descend {
+                        dirs.push(entry.path.clone());
+                    }
+                    self.add(entry);
+                }
+            }
+        }
+        self.0.refresh(id);
+        true
+    }
+
+    /// Reads the closest ancestor of `path` with a node in full, if `path` was folded into it
+    ///
+    /// Otherwise the directory at `path` was removed, as its parent's update
+    /// has already found.
+    fn update_folded(&mut self, path: &Path, dirs: &mut Vec<PathBuf>) -> bool {
+        let Some(id) = path.ancestors().skip(1).find_map(|p| self.0.find(p)) else {
+            return false;
+        };
+        if self.0[id].folded.is_none() {
+            return false;
+        }
+        let path = self.0.path_of(id);
+        self.requeue(id, self.depth_of(id));
+        dirs.push(path);
+        true
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        collections::{HashMap, HashSet},
+        ffi::{CString, OsStr, c_char},
+        fs::File,
+        io::{self, ErrorKind, Read},
+        os::{
+            fd::{AsRawFd, FromRawFd},
+            unix::ffi::OsStrExt,
+        },
+        path::{Path, PathBuf},
+    };
+
+    unsafe extern "C" {
+        fn inotify_init1(flags: i32) -> i32;
+        fn inotify_add_watch(fd: i32, path: *const c_char, mask: u32) -> i32;
+        fn inotify_rm_watch(fd: i32, wd: i32) -> i32;
+    }
+
+    const IN_NONBLOCK: i32 = 0o4000;
+    const IN_CLOEXEC: i32 = 0o2000000;
+
+    const IN_MODIFY: u32 = 0x2;
+    const IN_ATTRIB: u32 = 0x4;
+    const IN_MOVED_FROM: u32 = 0x40;
+    const IN_MOVED_TO: u32 = 0x80;
+    const IN_CREATE: u32 = 0x100;
+    const IN_DELETE: u32 = 0x200;
+    const IN_Q_OVERFLOW: u32 = 0x4000;
+    const IN_IGNORED: u32 = 0x8000;
+    const IN_ONLYDIR: u32 = 0x1000000;
+    const IN_ISDIR: u32 = 0x40000000;
+
+    /// the changes which may change the sizes or entries of a directory
+    const MASK: u32 =
+        IN_MODIFY | IN_ATTRIB | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE | IN_DELETE | IN_ONLYDIR;
+    /// the size of an event before its name: its watch, mask, cookie and name's length
+    const HEADER: usize = 16;
+
+    pub struct Watcher {
+        inotify: File,
+        /// the path of each watch, by its descriptor
+        watches: HashMap<i32, PathBuf>,
+        buf: Vec<u8>,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Self> {
+            // SAFETY: takes no pointers
+            let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                // SAFETY: the descriptor was just opened, so nothing else owns it
+                inotify: unsafe { File::from_raw_fd(fd) },
+                watches: HashMap::new(),
+                buf: vec![0; 64 * 1024],
+            })
+        }
+
+        pub fn add(&mut self, path: &Path) -> io::Result<()> {
+            let name = CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: `name` is nul-terminated
+            let wd = unsafe { inotify_add_watch(self.inotify.as_raw_fd(), name.as_ptr(), MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.watches.insert(wd, path.to_path_buf());
+            Ok(())
+        }
+
+        /// Adds the directories changed since the last call, returning whether any were lost
+        pub fn changes(&mut self, changed: &mut HashSet<PathBuf>) -> bool {
+            let Self {
+                inotify,
+                watches,
+                buf,
+            } = self;
+            let fd = inotify.as_raw_fd();
+
+            let mut lost = false;
+            loop {
+                let len = match inotify.read(buf) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return lost,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    // nothing can be known to be unchanged
+                    Err(_) => return true,
+                };
+
+                let mut rest = &buf[..len];
+                while rest.len() >= HEADER {
+                    let field = |i: usize| u32::from_ne_bytes(rest[i..i + 4].try_into().unwrap());
+                    let (wd, mask, len) = (field(0) as i32, field(4), field(12) as usize);
+                    let name = rest[HEADER..HEADER + len].split(|&b| b == 0).next();
+                    let name = OsStr::from_bytes(name.unwrap_or_default());
+                    rest = &rest[HEADER + len..];
+
+                    if mask & IN_Q_OVERFLOW != 0 {
+                        lost = true;
+                        continue;
+                    }
+                    if mask & IN_IGNORED != 0 {
+                        watches.remove(&wd);
+                        continue;
+                    }
+                    let Some(dir) = watches.get(&wd) else {
+                        continue;
+                    };
+                    changed.insert(dir.clone());
+
+                    // a moved directory keeps its watches, which would report it by its old path
+                    if mask & IN_ISDIR != 0 && mask & (IN_MOVED_FROM | IN_DELETE) != 0 {
+                        let gone = dir.join(name);
+                        watches.retain(|&wd, path| {
+                            let keep = !path.starts_with(&gone);
+                            if !keep {
+                                // SAFETY: takes no pointers
+                                unsafe { inotify_rm_watch(fd, wd) };
+                            }
+                            keep
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{
+        collections::{HashMap, HashSet},
+        hash::{DefaultHasher, Hash, Hasher},
+        io,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    };
+
+    /// What a directory looked like when last polled
+    #[derive(PartialEq)]
+    struct State {
+        mtime: Option<SystemTime>,
+        /// a hash of the name, size and modification time of each file
+        ///
+        /// A directory's own time only changes as entries are added or removed,
+        /// so this is what notices files being written to.
+        files: u64,
+    }
+
+    impl State {
+        fn read(path: &Path) -> io::Result<Self> {
+            let mtime = std::fs::metadata(path)?.modified().ok();
+            let mut files = 0u64;
+            for de in std::fs::read_dir(path)?.flatten() {
+                let Ok(md) = de.metadata() else {
+                    continue;
+                };
+                if md.is_dir() {
+                    continue;
+                }
+                let mut hasher = DefaultHasher::new();
+                (de.file_name(), md.len(), md.modified().ok()).hash(&mut hasher);
+                // summed, so the order entries are listed in doesn't matter
+                files = files.wrapping_add(hasher.finish());
+            }
+            Ok(Self { mtime, files })
+        }
+    }
+
+    pub struct Watcher {
+        /// each directory watched, with how it looked when last polled
+        dirs: HashMap<PathBuf, State>,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                dirs: HashMap::new(),
+            })
+        }
+
+        pub fn add(&mut self, path: &Path) -> io::Result<()> {
+            self.dirs.insert(path.to_path_buf(), State::read(path)?);
+            Ok(())
+        }
+
+        /// Adds the directories changed since the last call, returning whether any were lost
+        pub fn changes(&mut self, changed: &mut HashSet<PathBuf>) -> bool {
+            self.dirs.retain(|path, state| {
+                let Ok(now) = State::read(path) else {
+                    changed.insert(path.clone());
+                    // a directory which is gone is no longer watched
+                    return false;
+                };
+                if now != *state {
+                    changed.insert(path.clone());
+                    *state = now;
+                }
+                true
+            });
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test::{TempDir, scan};
+
+    /// The path, size and counts of every node, in no particular order
+    fn summary(stats: &Stats) -> Vec<(PathBuf, u64, u64, u64)> {
+        let mut nodes: Vec<_> = (stats.dfs(NodeId::ROOT))
+            .map(|(id, node)| {
+                (
+                    stats.path_of(id),
+                    node.info.size,
+                    node.info.files,
+                    node.info.dirs,
+                )
+            })
+            .collect();
+        nodes.sort();
+        nodes
+    }
+
+    #[test]
+    fn updates_follow_created_and_removed_entries() {
+        let dir = TempDir::new("watch");
+        dir.file("a/f", 10);
+        dir.file("b/g", 20);
+        dir.file("b/c/h", 30);
+        let mut du = scan(dir.path(), |_| {});
+        let mut watcher = du.watch().unwrap();
+        assert!(watcher.errors().is_empty());
+        assert_eq!(du.update(&mut watcher), 0);
+
+        dir.file("a/new", 5);
+        std::fs::remove_file(dir.path().join("b/g")).unwrap();
+        dir.file("d/e/i", 40);
+        assert!(du.update(&mut watcher) > 0);
+        let stats = du.stats();
+        assert!(stats.find(&dir.path().join("a/new")).is_some());
+        assert_eq!(stats.find(&dir.path().join("b/g")), None);
+        assert!(stats.find(&dir.path().join("d/e/i")).is_some());
+        assert_eq!(summary(stats), summary(scan(dir.path(), |_| {}).stats()));
+
+        // new directories are watched as well, and removed ones are let go
+        dir.file("d/e/j", 50);
+        std::fs::remove_dir_all(dir.path().join("b/c")).unwrap();
+        assert!(du.update(&mut watcher) > 0);
+        let stats = du.stats();
+        assert!(stats.find(&dir.path().join("d/e/j")).is_some());
+        assert_eq!(stats.find(&dir.path().join("b/c")), None);
+        assert_eq!(summary(stats), summary(scan(dir.path(), |_| {}).stats()));
+        assert_eq!(du.update(&mut watcher), 0);
+    }
+}
@@ -0,0 +1,469 @@
+//! formatting sizes and trees for display
+//!
+//! Everything here implements [`fmt::Display`], so it can be written
+//! straight to any formatter, with widths and alignment applying to sizes.
+
+use std::fmt;
+
+use crate::du::{NodeId, SizeMode, Stats};
+
+/// A size in bytes, written in the largest unit it's at least one of, e.g. `1.50 KiB`
+///
+/// By default units are powers of 1024, and sizes are written with three
+/// significant digits. A size is never written with four digits before the
+/// point, so from 1000 of a unit it's written in the next, like `0.99 MiB`.
+/// Bytes are always written whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanSize {
+    bytes: u64,
+    binary: bool,
+    precision: Option<usize>,
+}
+
+impl HumanSize {
+    pub fn new(bytes: u64) -> Self {
+        Self {
+            bytes,
+            binary: true,
+            precision: None,
+        }
+    }
+
+    /// Use powers of 1024 if `binary`, and otherwise powers of 1000
+    pub fn binary(mut self, binary: bool) -> Self {
+        self.binary = binary;
+        self
+    }
+
+    /// Write exactly `digits` digits after the decimal point, rather than three significant digits
+    pub fn precision(mut self, digits: usize) -> Self {
+        self.precision = Some(digits);
+        self
+    }
+
+    /// The digits after the decimal point of `value`
+    fn decimals(&self, value: f64) -> usize {
+        self.precision.unwrap_or(match value {
+            ..10.0 => 2,
+            ..100.0 => 1,
+            _ => 0,
+        })
+    }
+}
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.bytes;
+        let mut unit = ByteUnit::of(bytes, self.binary);
+        if unit == ByteUnit::Byte {
+            return f.pad(&format!("{bytes} {unit}"));
+        }
+
+        // carried into the next unit once it takes four digits, including by
+        // rounding, e.g. 999.9 KB is 1.00 MB and 1015.6 KiB is 0.99 MiB
+        let value = unit.scale(bytes);
+        let d = self.decimals(value);
+        let rounded = format!("{value:.d$}").parse().unwrap_or(value);
+        if let Some(next) = unit.next()
+            && rounded >= 1000.0
+        {
+            unit = next;
+        }
+
+        let value = unit.scale(bytes);
+        f.pad(&format!("{value:.d$} {unit}", d = self.decimals(value)))
+    }
+}
+
+/// A unit of bytes, either 1000 or 1024 based
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteUnit {
+    Byte,
+    Kilo,
+    Mega,
+    Giga,
+    Tera,
+    Peta,
+    Exa,
+    Kibi,
+    Mebi,
+    Gibi,
+    Tebi,
+    Pebi,
+    Exbi,
+}
+
+impl ByteUnit {
+    const DECIMAL: [Self; 7] = [
+        Self::Byte,
+        Self::Kilo,
+        Self::Mega,
+        Self::Giga,
+        Self::Tera,
+        Self::Peta,
+        Self::Exa,
+    ];
+    const BINARY: [Self; 7] = [
+        Self::Byte,
+        Self::Kibi,
+        Self::Mebi,
+        Self::Gibi,
+        Self::Tebi,
+        Self::Pebi,
+        Self::Exbi,
+    ];
+
+    /// The largest unit of which `bytes` is at least one
+    pub fn of(bytes: u64, binary: bool) -> Self {
+        let units = if binary { Self::BINARY } else { Self::DECIMAL };
+        (units.into_iter().rev())
+            .find(|unit| bytes >= unit.bytes())
+            .unwrap_or(Self::Byte)
+    }
+
+    /// The bytes in one of this unit
+    pub fn bytes(self) -> u64 {
+        let (base, exp) = self.base_exp();
+        base.pow(exp)
+    }
+
+    /// `bytes` measured in this unit
+    pub fn scale(self, bytes: u64) -> f64 {
+        bytes as f64 / self.bytes() as f64
+    }
+
+    /// The next larger unit of the same base
+    pub fn next(self) -> Option<Self> {
+        let (base, exp) = self.base_exp();
+        let units = if base == 1024 {
+            Self::BINARY
+        } else {
+            Self::DECIMAL
+        };
+        units.get(exp as usize + 1).copied()
+    }
+
+    fn base_exp(self) -> (u64, u32) {
+        match self {
+            Self::Byte => (1000, 0),
+            Self::Kilo => (1000, 1),
+            Self::Mega => (1000, 2),
+            Self::Giga => (1000, 3),
+            Self::Tera => (1000, 4),
+            Self::Peta => (1000, 5),
+            Self::Exa => (1000, 6),
+            Self::Kibi => (1024, 1),
+            Self::Mebi => (1024, 2),
+            Self::Gibi => (1024, 3),
+            Self::Tebi => (1024, 4),
+            Self::Pebi => (1024, 5),
+            Self::Exbi => (1024, 6),
+        }
+    }
+}
+
+impl fmt::Display for ByteUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Byte => "B",
+            Self::Kilo => "KB",
+            Self::Mega => "MB",
+            Self::Giga => "GB",
+            Self::Tera => "TB",
+            Self::Peta => "PB",
+            Self::Exa => "EB",
+            Self::Kibi => "KiB",
+            Self::Mebi => "MiB",
+            Self::Gibi => "GiB",
+            Self::Tebi => "TiB",
+            Self::Pebi => "PiB",
+            Self::Exbi => "EiB",
+        })
+    }
+}
+
+/// The percentage of `whole` which `part` is, or zero if `whole` is
+pub fn percent(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 / whole as f64 * 100.0,
+    }
+}
+
+/// The percentage of its parent's size which `id` takes, where the head takes all of itself
+pub fn percent_of_parent(stats: &Stats, id: NodeId, mode: SizeMode) -> f64 {
+    let parent = stats[id].parent_id();
+    match parent == id {
+        true => 100.0,
+        false => percent(stats.size(id, mode), stats.size(parent, mode)),
+    }
+}
+
+/// A bar filled by the share of a whole which a part takes, followed by the percentage
+///
+/// Written like `│█████░░░░░│  50%`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bar {
+    percent: f64,
+    width: usize,
+}
+
+impl Bar {
+    /// A bar of `width` characters between its ends
+    pub fn new(part: u64, whole: u64, width: usize) -> Self {
+        Self {
+            percent: percent(part, whole),
+            width,
+        }
+    }
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let filled = ((self.percent / 100.0 * self.width as f64).round() as usize).min(self.width);
+        write!(
+            f,
+            "│{}{}│ {:>3.0}%",
+            "█".repeat(filled),
+            "░".repeat(self.width - filled),
+            self.percent,
+        )
+    }
+}
+
+/// The tree of a scan, written a line per entry with its size and share of the total
+///
+/// Children are written largest first, beneath their parent and joined to
+/// it by lines, like `tree`:
+///
+/// ```text
+///    203 KiB │████████████████████│ 100%  src
+///   89.1 KiB │█████████░░░░░░░░░░░│  44%  ├─ du
+///   64.7 KiB │██████░░░░░░░░░░░░░░│  32%  └─ du.rs
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Tree<'a> {
+    stats: &'a Stats,
+    from: NodeId,
+    depth: usize,
+    mode: SizeMode,
+    binary: bool,
+    bar_width: usize,
+}
+
+impl<'a> Tree<'a> {
+    /// The tree of `stats` from its head, down to its children
+    pub fn new(stats: &'a Stats) -> Self {
+        Self {
+            stats,
+            from: NodeId::ROOT,
+            depth: 1,
+            mode: SizeMode::Apparent,
+            binary: true,
+            bar_width: 20,
+        }
+    }
+
+    /// Start the tree at `id`, whose share is then of its own size
+    pub fn from(mut self, id: NodeId) -> Self {
+        self.from = id;
+        self
+    }
+
+    /// Write entries down to `depth` levels below the first
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Write and sort by the size measured by `mode`
+    pub fn size_mode(mut self, mode: SizeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Write sizes in powers of 1024 if `binary`, see [`HumanSize::binary`]
+    pub fn binary(mut self, binary: bool) -> Self {
+        self.binary = binary;
+        self
+    }
+
+    /// Make bars `width` characters wide, leaving them out entirely at zero
+    pub fn bar_width(mut self, width: usize) -> Self {
+        self.bar_width = width;
+        self
+    }
+
+    /// The children of `id`, largest first with ties broken by name
+    fn children(&self, id: NodeId) -> Vec<NodeId> {
+        let stats = self.stats;
+        let mut children = stats.children(id).to_vec();
+        children.sort_by(|&a, &b| {
+            (stats.size(b, self.mode).cmp(&stats.size(a, self.mode)))
+                .then_with(|| stats.name(a).cmp(stats.name(b)))
+        });
+        children
+    }
+}
+
+impl fmt::Display for Tree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats;
+        let total = stats.size(self.from, self.mode);
+        // the entries left to write, along with the prefix drawing their ancestors' lines
+        let mut stack = vec![(self.from, 0, String::new(), true)];
+        while let Some((id, level, prefix, last)) = stack.pop() {
+            let size = stats.size(id, self.mode);
+            // the head of several paths has no name of its own
+            let name = match stats.name(id) {
+                name if name.is_empty() => "(total)".into(),
+                name => name.to_string_lossy(),
+            };
+            let branch = match (level, last) {
+                (0, _) => "",
+                (_, true) => "└─ ",
+                (_, false) => "├─ ",
+            };
+            write!(f, "{:>10} ", HumanSize::new(size).binary(self.binary))?;
+            if self.bar_width > 0 {
+                write!(f, "{} ", Bar::new(size, total, self.bar_width))?;
+            }
+            writeln!(f, " {prefix}{branch}{name}")?;
+
+            if level < self.depth {
+                let prefix = match (level, last) {
+                    (0, _) => prefix,
+                    (_, true) => prefix + "   ",
+                    (_, false) => prefix + "│  ",
+                };
+                let children = self.children(id);
+                let count = children.len();
+                for (i, child) in children.into_iter().enumerate().rev() {
+                    stack.push((child, level + 1, prefix.clone(), i + 1 == count));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test::{TempDir, scan};
+
+    #[test]
+    fn human_size_picks_units_and_digits() {
+        let cases = [
+            (0, true, "0 B"),
+            (1023, true, "1023 B"),
+            (1024, true, "1.00 KiB"),
+            (1536, true, "1.50 KiB"),
+            (10 * 1024, true, "10.0 KiB"),
+            (999_999, false, "1.00 MB"),
+            (123_456, false, "123 KB"),
+            (u64::MAX, true, "16.0 EiB"),
+            (u64::MAX, false, "18.4 EB"),
+        ];
+        for (bytes, binary, written) in cases {
+            assert_eq!(HumanSize::new(bytes).binary(binary).to_string(), written);
+        }
+    }
+
+    #[test]
+    fn human_size_at_the_edges() {
+        let cases = [
+            (0, "0 B", "0 B"),
+            (1023, "1023 B", "1.02 KB"),
+            (1024, "1.00 KiB", "1.02 KB"),
+            (999_999, "977 KiB", "1.00 MB"),
+            (u64::MAX, "16.0 EiB", "18.4 EB"),
+        ];
+        for (bytes, binary, decimal) in cases {
+            let size = HumanSize::new(bytes);
+            assert_eq!(size.to_string(), binary, "{bytes} in binary");
+            assert_eq!(
+                size.binary(false).to_string(),
+                decimal,
+                "{bytes} in decimal"
+            );
+        }
+    }
+
+    #[test]
+    fn human_size_carries_at_four_digits() {
+        assert_eq!(HumanSize::new(1_040_000).to_string(), "0.99 MiB");
+        assert_eq!(HumanSize::new(1_023_999).to_string(), "0.98 MiB");
+        assert_eq!(HumanSize::new(1000 * 1024 - 1).to_string(), "0.98 MiB");
+        assert_eq!(HumanSize::new(999 * 1024).to_string(), "999 KiB");
+        assert_eq!(HumanSize::new(999_500).binary(false).to_string(), "1.00 MB");
+    }
+
+    #[test]
+    fn human_size_precision_and_padding() {
+        assert_eq!(HumanSize::new(1536).precision(0).to_string(), "2 KiB");
+        assert_eq!(HumanSize::new(1536).precision(3).to_string(), "1.500 KiB");
+        assert_eq!(format!("{:>10}", HumanSize::new(1536)), "  1.50 KiB");
+        assert_eq!(format!("{:<6}|", HumanSize::new(5)), "5 B   |");
+    }
+
+    #[test]
+    fn byte_units_step_by_their_base() {
+        assert_eq!(ByteUnit::of(0, true), ByteUnit::Byte);
+        assert_eq!(ByteUnit::of(1023, true), ByteUnit::Byte);
+        assert_eq!(ByteUnit::of(1024, true), ByteUnit::Kibi);
+        assert_eq!(ByteUnit::of(1000, false), ByteUnit::Kilo);
+        assert_eq!(ByteUnit::of(u64::MAX, true), ByteUnit::Exbi);
+        assert_eq!(ByteUnit::Kibi.next(), Some(ByteUnit::Mebi));
+        assert_eq!(ByteUnit::Kilo.next(), Some(ByteUnit::Mega));
+        assert_eq!(ByteUnit::Exa.next(), None);
+        assert_eq!(ByteUnit::Mebi.bytes(), 1 << 20);
+        assert_eq!(ByteUnit::Giga.scale(1_500_000_000), 1.5);
+    }
+
+    #[test]
+    fn bar_fills_by_share() {
+        assert_eq!(Bar::new(0, 10, 4).to_string(), "│░░░░│   0%");
+        assert_eq!(Bar::new(5, 10, 4).to_string(), "│██░░│  50%");
+        assert_eq!(Bar::new(10, 10, 4).to_string(), "│████│ 100%");
+        // an empty whole is an empty bar, rather than a division by zero
+        assert_eq!(Bar::new(3, 0, 4).to_string(), "│░░░░│   0%");
+    }
+
+    #[test]
+    fn bar_clamps_above_whole() {
+        assert_eq!(Bar::new(30, 10, 4).to_string(), "│████│ 300%");
+        assert_eq!(Bar::new(30, 10, 0).to_string(), "││ 300%");
+    }
+
+    #[test]
+    fn tree_draws_children_largest_first() {
+        let dir = TempDir::new("format-tree");
+        dir.file("top/big", 30_000);
+        dir.file("top/sub/small", 100);
+        dir.file("top/sub/smaller", 10);
+        dir.file("top/mid", 20_000);
+        let du = scan(dir.path(), |_| {});
+        let stats = du.stats();
+        let top = stats.find(&dir.path().join("top")).unwrap();
+
+        let size = |path: &str| {
+            let id = stats.find(&dir.path().join(path)).unwrap();
+            HumanSize::new(stats.size(id, SizeMode::Apparent))
+        };
+        let tree = Tree::new(stats).from(top).depth(2).bar_width(0);
+        let expected = [
+            format!("{:>10}  top", size("top")),
+            format!("{:>10}  ├─ big", size("top/big")),
+            format!("{:>10}  ├─ mid", size("top/mid")),
+            format!("{:>10}  └─ sub", size("top/sub")),
+            format!("{:>10}     ├─ small", size("top/sub/small")),
+            format!("{:>10}     └─ smaller", size("top/sub/smaller")),
+        ];
+        assert_eq!(tree.to_string().lines().collect::<Vec<_>>(), expected);
+
+        let shallow = Tree::new(stats).from(top).bar_width(4).to_string();
+        assert_eq!(shallow.lines().count(), 4);
+        assert!(shallow.lines().next().unwrap().contains("│████│ 100%  top"));
+    }
+}
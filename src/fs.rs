@@ -0,0 +1,182 @@
+//! the capacity and free space of filesystems
+//!
+//! This is found through `statvfs` on Unix and `GetDiskFreeSpaceExW` on
+//! Windows. [`Du::volumes`](crate::du::Du::volumes) uses it to put each root
+//! of a scan in the context of the filesystem it's on.
+
+use std::{io, path::Path};
+
+use crate::{du::NodeId, format};
+
+/// The capacity and free space of a filesystem, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Space {
+    pub total: u64,
+    /// free to anyone, including the space reserved for root
+    pub free: u64,
+    /// free to the current user
+    pub available: u64,
+}
+
+impl Space {
+    /// The space taken, by anything at all
+    pub fn used(&self) -> u64 {
+        self.total.saturating_sub(self.free)
+    }
+
+    /// The percentage of the whole filesystem which `bytes` take
+    pub fn percent_of(&self, bytes: u64) -> f64 {
+        format::percent(bytes, self.total)
+    }
+}
+
+/// A root of a scan along with the filesystem it's on, see [`Du::volumes`](crate::du::Du::volumes)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume {
+    pub root: NodeId,
+    pub space: Space,
+    /// the allocated size of the root, with its contents
+    pub alloc: u64,
+}
+
+impl Volume {
+    /// The percentage of the filesystem which the root takes
+    pub fn percent(&self) -> f64 {
+        self.space.percent_of(self.alloc)
+    }
+}
+
+/// The space of the filesystem holding `path`
+pub fn space(path: &Path) -> io::Result<Space> {
+    sys::space(path)
+}
+
+#[cfg(any(target_os = "linux", target_vendor = "apple", target_os = "freebsd"))]
+mod sys {
+    use std::{
+        ffi::{CString, c_char, c_int, c_ulong},
+        io,
+        os::unix::ffi::OsStrExt,
+        path::Path,
+    };
+
+    use super::Space;
+
+    /// the type of the block counts, which only some platforms keep to 32 bits
+    #[cfg(any(
+        target_vendor = "apple",
+        all(target_os = "linux", target_env = "gnu", target_pointer_width = "32"),
+    ))]
+    type Blocks = u32;
+    #[cfg(not(any(
+        target_vendor = "apple",
+        all(target_os = "linux", target_env = "gnu", target_pointer_width = "32"),
+    )))]
+    type Blocks = u64;
+
+    /// The leading fields of `struct statvfs`, as laid out on Linux and Apple platforms
+    #[cfg(not(target_os = "freebsd"))]
+    #[repr(C)]
+    struct Statvfs {
+        bsize: c_ulong,
+        frsize: c_ulong,
+        blocks: Blocks,
+        bfree: Blocks,
+        bavail: Blocks,
+        /// larger than the rest of the fields, wherever they differ
+        _rest: [u64; 16],
+    }
+
+    /// `struct statvfs` as laid out on FreeBSD, where the block counts come first
+    #[cfg(target_os = "freebsd")]
+    #[repr(C)]
+    struct Statvfs {
+        bavail: Blocks,
+        bfree: Blocks,
+        blocks: Blocks,
+        _favail: u64,
+        _ffree: u64,
+        _files: u64,
+        bsize: c_ulong,
+        _flag: c_ulong,
+        frsize: c_ulong,
+        _fsid: c_ulong,
+        _namemax: c_ulong,
+    }
+
+    unsafe extern "C" {
+        fn statvfs(path: *const c_char, buf: *mut Statvfs) -> c_int;
+    }
+
+    pub fn space(path: &Path) -> io::Result<Space> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: zeroed fields are valid, as they're only integers
+        let mut buf: Statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `path` is nul-terminated and `buf` is large enough for the whole struct
+        if unsafe { statvfs(path.as_ptr(), &mut buf) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // blocks are counted in fragments, which are only left unset by old systems
+        let size = match buf.frsize {
+            0 => buf.bsize,
+            frsize => frsize,
+        } as u64;
+        // the counts are only narrower than `u64` on some platforms
+        #[allow(clippy::unnecessary_cast)]
+        let bytes = |blocks: Blocks| blocks as u64 * size;
+        Ok(Space {
+            total: bytes(buf.blocks),
+            free: bytes(buf.bfree),
+            available: bytes(buf.bavail),
+        })
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::{io, os::windows::ffi::OsStrExt, path::Path};
+
+    use super::Space;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetDiskFreeSpaceExW(
+            name: *const u16,
+            available: *mut u64,
+            total: *mut u64,
+            free: *mut u64,
+        ) -> i32;
+    }
+
+    pub fn space(path: &Path) -> io::Result<Space> {
+        let name: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+        let (mut available, mut total, mut free) = (0, 0, 0);
+        // SAFETY: `name` is nul-terminated and the rest are valid out pointers
+        if unsafe { GetDiskFreeSpaceExW(name.as_ptr(), &mut available, &mut total, &mut free) } == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Space {
+            total,
+            free,
+            available,
+        })
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_vendor = "apple",
+    target_os = "freebsd",
+    windows,
+)))]
+mod sys {
+    use std::{io, path::Path};
+
+    use super::Space;
+
+    pub fn space(_path: &Path) -> io::Result<Space> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
@@ -1,3 +1,5 @@
 pub mod du;
+pub mod format;
+pub mod fs;
 pub mod ui;
 pub mod util;
@@ -1,10 +1,21 @@
-use std::ffi::OsStr;
-use std::io::Error;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{DirEntry, Metadata};
 use std::io::ErrorKind;
 use std::io::Result;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::{mem, vec};
 
-use crate::du::{Entry, FileKind, Info, NodeId};
+use crate::du::filter::Ignores;
+use crate::du::{
+    Counters, DuSource, Entry, FileKind, Info, Inode, NextEntry, NodeId, Options, Owner, ScanError,
+    ScanOp, SizeMode, SymlinkPolicy, WAIT,
+};
+use crate::format::HumanSize;
 
 pub fn get_name(path: &Path) -> Result<&OsStr> {
     match path.components().next_back() {
@@ -13,33 +24,1320 @@ pub fn get_name(path: &Path) -> Result<&OsStr> {
     }
 }
 
+/// Moved to [`format`](crate::format), along with the rest of size formatting
+pub use crate::format::ByteUnit;
+
+/// Formats `bytes` with three significant digits, e.g. `1.50 KiB` or `999 B`
+///
+/// See [`HumanSize`] for more control over the format.
+#[deprecated(note = "use `format::HumanSize`, which this only wraps")]
+pub fn human_size(bytes: u64, binary: bool) -> String {
+    HumanSize::new(bytes).binary(binary).to_string()
+}
+
+#[cfg(unix)]
+pub fn get_inode(md: &Metadata) -> Option<Inode> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(Inode {
+        dev: md.dev(),
+        ino: md.ino(),
+        nlink: md.nlink(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn get_inode(_md: &Metadata) -> Option<Inode> {
+    None
+}
+
+#[cfg(unix)]
+pub fn get_owner(md: &Metadata) -> Option<Owner> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(Owner {
+        uid: md.uid(),
+        gid: md.gid(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn get_owner(_md: &Metadata) -> Option<Owner> {
+    None
+}
+
+/// The allocated size of the entry at `path`, or its apparent size if unknown
+///
+/// Unix reports blocks with the metadata, so `mode` is ignored.
+#[cfg(unix)]
+pub fn get_alloc(_path: &Path, md: &Metadata, _mode: SizeMode) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    md.blocks() * 512
+}
+
+/// The allocated size of the entry at `path`, or its apparent size if unknown
+///
+/// Windows needs an extra call per file, so it's only made in
+/// [`SizeMode::Allocated`]. Compressed and sparse files report the space
+/// they actually take.
+#[cfg(windows)]
+pub fn get_alloc(path: &Path, md: &Metadata, mode: SizeMode) -> u64 {
+    match mode == SizeMode::Allocated && md.is_file() {
+        true => get_compressed_size(path).unwrap_or(md.len()),
+        false => md.len(),
+    }
+}
+
+/// The space the file at `path` takes, if it could be found
+#[cfg(windows)]
+fn get_compressed_size(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCompressedFileSizeW(name: *const u16, high: *mut u32) -> u32;
+    }
+
+    let name: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut high = 0;
+    // SAFETY: `name` is nul-terminated and `high` is a valid out pointer
+    let low = unsafe { GetCompressedFileSizeW(name.as_ptr(), &mut high) };
+    // a low of u32::MAX is only a failure if an error was also set
+    if low == u32::MAX && std::io::Error::last_os_error().raw_os_error() != Some(0) {
+        return None;
+    }
+    Some((high as u64) << 32 | low as u64)
+}
+
+/// The allocated size of the entry at `path`, or its apparent size if unknown
+#[cfg(not(any(unix, windows)))]
+pub fn get_alloc(_path: &Path, md: &Metadata, _mode: SizeMode) -> u64 {
+    md.len()
+}
+
+pub fn get_info(
+    name: impl Into<Box<OsStr>>,
+    path: &Path,
+    md: &Metadata,
+    options: &Options,
+) -> Info {
+    let mut info = Info::new(name, FileKind::from(md.file_type()), md.len());
+    info.inode = get_inode(md);
+    info.mtime = md.modified().ok();
+    info.atime = options.atime.then(|| md.accessed().ok()).flatten();
+    info.owner = options.owners.then(|| get_owner(md)).flatten();
+    set_sizes(
+        &mut info,
+        options,
+        md.len(),
+        get_alloc(path, md, options.size_mode),
+    );
+    info
+}
+
+/// Sets the sizes of `info`, along with what depends on the rest of its metadata
+fn set_sizes(info: &mut Info, options: &Options, own: u64, alloc: u64) {
+    info.old = (options.older_than).is_some_and(|cutoff| info.mtime.is_some_and(|m| m < cutoff));
+    // sets the totals, so it comes after everything they depend on
+    info.set_own(own, alloc);
+}
+
+/// The info of a directory being scanned, named by its whole `path`
+///
+/// This follows symlinks, as reading the directory does. Its own size is
+/// left at zero in a dry run, or if its metadata can't be read, leaving the
+/// error to be found when the directory is.
+pub fn get_root_info(path: &Path, options: &Options) -> Info {
+    let md = match options.dry_run {
+        true => None,
+        false => std::fs::metadata(path).ok(),
+    };
+    match md {
+        Some(md) => get_info(path.as_os_str(), path, &md, options),
+        None => Info::new(path.as_os_str(), FileKind::Dir, 0),
+    }
+}
+
+/// The device the entry at `path` is stored on, if known
+///
+/// This is the device number on Unix, found in `inode`, and the volume serial
+/// number on Windows.
+#[cfg(unix)]
+pub fn get_dev(_path: &Path, inode: Option<Inode>) -> Option<u64> {
+    inode.map(|inode| inode.dev)
+}
+
+/// The device the entry at `path` is stored on, if known
+///
+/// This is the device number on Unix, found in `inode`, and the volume serial
+/// number on Windows.
+#[cfg(windows)]
+pub fn get_dev(path: &Path, _inode: Option<Inode>) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::null_mut;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetVolumePathNameW(name: *const u16, volume: *mut u16, len: u32) -> i32;
+        fn GetVolumeInformationW(
+            root: *const u16,
+            name: *mut u16,
+            name_len: u32,
+            serial: *mut u32,
+            max_component_len: *mut u32,
+            flags: *mut u32,
+            fs_name: *mut u16,
+            fs_name_len: u32,
+        ) -> i32;
+    }
+
+    let name: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    // the volume's path is never longer than the path within it
+    let mut volume = vec![0; name.len().max(261)];
+    // SAFETY: `name` is nul-terminated and `volume` is as long as given
+    let found =
+        unsafe { GetVolumePathNameW(name.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) };
+    if found == 0 {
+        return None;
+    }
+
+    let mut serial = 0;
+    // SAFETY: `volume` was nul-terminated by the last call, and the buffers
+    // which aren't wanted are null with a length of zero
+    let found = unsafe {
+        GetVolumeInformationW(
+            volume.as_ptr(),
+            null_mut(),
+            0,
+            &mut serial,
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            0,
+        )
+    };
+    (found != 0).then_some(serial as u64)
+}
+
+/// The device the entry at `path` is stored on, if known
+#[cfg(not(any(unix, windows)))]
+pub fn get_dev(_path: &Path, _inode: Option<Inode>) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+type FileId = (u64, u64);
+
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+#[cfg(unix)]
+fn get_file_id(_path: &Path, inode: Option<Inode>) -> Option<FileId> {
+    inode.map(|inode| (inode.dev, inode.ino))
+}
+
+#[cfg(not(unix))]
+fn get_file_id(path: &Path, _inode: Option<Inode>) -> Option<FileId> {
+    std::fs::canonicalize(path).ok()
+}
+
+/// State shared by every directory read during a scan
+#[derive(Debug, Default)]
+pub struct ScanState {
+    counters: Counters,
+    /// directories seen while following symlinks
+    visited: Mutex<HashSet<FileId>>,
+    /// hardlinked files seen while deduplicating
+    links: Mutex<HashSet<(u64, u64)>>,
+    /// the directories with ignore files, along with every rule applying within them
+    ignores: Mutex<HashMap<PathBuf, Arc<Ignores>>>,
+}
+
+impl ScanState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub fn reset(&mut self) {
+        self.counters.reset();
+        self.visited.get_mut().unwrap().clear();
+        self.links.get_mut().unwrap().clear();
+        self.ignores.get_mut().unwrap().clear();
+    }
+
+    /// marks a hardlink as seen, returning whether it's the first of its inode
+    fn first_link(&self, inode: Inode) -> bool {
+        self.links.lock().unwrap().insert((inode.dev, inode.ino))
+    }
+
+    /// unmarks hardlinks as seen, so each is counted when next found
+    pub fn forget_links(&self, links: &[Inode]) {
+        let mut seen = self.links.lock().unwrap();
+        for inode in links {
+            seen.remove(&(inode.dev, inode.ino));
+        }
+    }
+
+    /// reads the ignore files of the directory at `path`, returning every rule applying within it
+    ///
+    /// Directories are always read after their parents, so the rules of
+    /// each ancestor are already known.
+    fn ignores(&self, path: &Path, names: &[OsString]) -> Option<Arc<Ignores>> {
+        let parent = {
+            let ignores = self.ignores.lock().unwrap();
+            path.ancestors()
+                .skip(1)
+                .find_map(|p| ignores.get(p).cloned())
+        };
+        // the files are read again on a rescan, so any which were removed are forgotten
+        match Ignores::read(path, names, parent.clone()) {
+            Some(own) => {
+                let own = Arc::new(own);
+                self.ignores
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_path_buf(), own.clone());
+                Some(own)
+            }
+            None => {
+                self.ignores.lock().unwrap().remove(path);
+                parent
+            }
+        }
+    }
+
+    /// marks a directory as seen, returning whether it's new to the scan
+    fn visit(&self, path: &Path, inode: Option<Inode>) -> bool {
+        match get_file_id(path, inode) {
+            Some(id) => self.visited.lock().unwrap().insert(id),
+            None => true,
+        }
+    }
+}
+
+thread_local! {
+    /// reused by [`read_dir`] to build entry paths without reallocating
+    static PATH: RefCell<PathBuf> = const { RefCell::new(PathBuf::new()) };
+}
+
+/// Reads the entries of the directory at `path`, giving each to `entry` and any errors to `error`
+///
+/// Linux and Windows read each entry's metadata with calls of their own,
+/// which are cheaper than those of [`std::fs`]. Elsewhere, or when those
+/// calls aren't available, [`std::fs::read_dir`] is used instead.
 pub fn read_dir(
+    options: &Options,
+    scan: &ScanState,
     parent: NodeId,
+    depth: usize,
     path: &Path,
     mut entry: impl FnMut(Entry),
-    mut error: impl FnMut(Error),
+    mut error: impl FnMut(ScanError),
 ) {
-    macro_rules! handle {
-        ($e:expr) => {
-            match $e {
-                Ok(value) => value,
-                Err(e) => {
-                    error(e);
-                    return;
+    // the scan's roots are never entries, so they're only seen here
+    let visit_root = options.symlinks == SymlinkPolicy::Follow && depth == 0;
+    let md = match visit_root || options.one_filesystem {
+        true => {
+            scan.counters.add_stat_call();
+            std::fs::metadata(path).ok()
+        }
+        false => None,
+    };
+    if visit_root && let Some(md) = &md {
+        scan.visit(path, get_inode(md));
+    }
+    let dir = Dir {
+        parent,
+        depth,
+        dev: match options.one_filesystem {
+            true => get_dev(path, md.as_ref().and_then(get_inode)),
+            false => None,
+        },
+        ignores: match options.ignore_files.is_empty() {
+            true => None,
+            false => scan.ignores(path, &options.ignore_files),
+        },
+    };
+
+    scan.counters.add_dir_read();
+    let mut found = |path: &Path, info: Result<Info>| match info {
+        Ok(info) => {
+            if let Some(e) = read_entry(options, scan, &dir, info, path) {
+                scan.counters.add_entry_read();
+                entry(e);
+            }
+        }
+        // only this entry is lost, the rest of the directory is still read
+        Err(e) => error(ScanError::new(path, ScanOp::Metadata, parent, e)),
+    };
+    // a directory only stands for a symlink's target if it's a root or was followed
+    let follow = depth == 0 || options.symlinks == SymlinkPolicy::Follow;
+    if let Err((op, e)) = sys::read_dir(options, scan, path, follow, &mut found) {
+        error(ScanError::new(path, op, parent, e));
+    }
+}
+
+/// A batch of entries, along with any errors hit while reading them
+pub(crate) type Message = (Vec<Entry>, Vec<ScanError>);
+
+/// Reads the entries of the directory at `path` all at once, for [`DuSource::read_dir_now`]
+pub(crate) fn read_dir_now(
+    options: &Options,
+    scan: &ScanState,
+    parent: NodeId,
+    depth: usize,
+    path: &Path,
+    errors: &mut Vec<ScanError>,
+) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    read_dir(
+        options,
+        scan,
+        parent,
+        depth,
+        path,
+        |e| entries.push(e),
+        |e| errors.push(e),
+    );
+    entries
+}
+
+/// Reads the directory at `path` like [`read_dir`], sending its entries in batches of `batch_size`
+///
+/// The last batch is sent as soon as the directory is read, along with its
+/// errors, unless both are empty.
+pub(crate) fn read_dir_batched(
+    options: &Options,
+    scan: &ScanState,
+    parent: NodeId,
+    depth: usize,
+    path: &Path,
+    batch_size: usize,
+    mut send: impl FnMut(Message),
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut errors = Vec::new();
+    read_dir(
+        options,
+        scan,
+        parent,
+        depth,
+        path,
+        |e| {
+            batch.push(e);
+            if batch.len() >= batch_size {
+                send((
+                    mem::replace(&mut batch, Vec::with_capacity(batch_size)),
+                    Vec::new(),
+                ));
+            }
+        },
+        |e| errors.push(e),
+    );
+    if !batch.is_empty() || !errors.is_empty() {
+        send((batch, errors));
+    }
+}
+
+/// The batches received by a threaded source, with the errors sent along with them
+#[derive(Default)]
+pub(crate) struct Batches {
+    /// the rest of the last batch received
+    batch: vec::IntoIter<Entry>,
+    /// entries taken out of the batches
+    consumed: usize,
+    errors: Vec<ScanError>,
+}
+
+impl Batches {
+    /// Takes the next entry, calling `recv` for another batch whenever one runs out
+    ///
+    /// `recv` gives `None` once every batch has been received, which then
+    /// finishes the entries.
+    pub fn next(&mut self, mut recv: impl FnMut() -> Poll<Option<Message>>) -> NextEntry {
+        loop {
+            if let Some(entry) = self.batch.next() {
+                self.consumed += 1;
+                return NextEntry::Ready(entry);
+            }
+            match recv() {
+                Poll::Ready(Some((entries, errors))) => {
+                    self.batch = entries.into_iter();
+                    self.errors.extend(errors);
                 }
+                Poll::Ready(None) => return NextEntry::Finished,
+                Poll::Pending => return NextEntry::Pending,
             }
+        }
+    }
+
+    /// The entries taken so far, so those received but not yet taken are known
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    pub fn errors(&self) -> &[ScanError] {
+        &self.errors
+    }
+
+    pub fn errors_mut(&mut self) -> &mut Vec<ScanError> {
+        &mut self.errors
+    }
+
+    pub fn clear(&mut self) {
+        self.batch = Vec::new().into_iter();
+        self.consumed = 0;
+        self.errors.clear();
+    }
+}
+
+/// Takes the next entry of `source`, sleeping until one is ready or its scan is finished
+///
+/// For the [`DuSource::next_entry`] of sources which wait on their entries.
+pub(crate) fn next_entry(source: &mut impl DuSource) -> Option<Entry> {
+    loop {
+        match source.wait_entry(WAIT) {
+            NextEntry::Ready(entry) => return Some(entry),
+            NextEntry::Pending => {}
+            NextEntry::Finished => return None,
+        }
+    }
+}
+
+/// Called with the path and info of each entry read, or the error reading its metadata
+type Found<'a> = dyn FnMut(&Path, Result<Info>) + 'a;
+
+/// The error which stopped a directory from being read any further
+type ReadError = (ScanOp, std::io::Error);
+
+/// Reads the directory at `path` through [`std::fs::read_dir`], the portable way
+///
+/// This makes a call for the metadata of each entry, after reading the
+/// directory itself. It always follows a symlink at `path`, whether or not
+/// it should `follow` one.
+#[cfg_attr(windows, allow(dead_code))]
+fn read_std(
+    options: &Options,
+    scan: &ScanState,
+    path: &Path,
+    _follow: bool,
+    found: &mut Found,
+) -> std::result::Result<(), ReadError> {
+    let read = std::fs::read_dir(path).map_err(|e| (ScanOp::ReadDir, e))?;
+    PATH.with_borrow_mut(|buf| {
+        buf.as_mut_os_string().clear();
+        buf.push(path);
+
+        for value in read {
+            if options.cancel.is_cancelled() {
+                break;
+            }
+            let de = value.map_err(|e| (ScanOp::NextEntry, e))?;
+            let name = de.file_name();
+            buf.push(&name);
+            found(buf, get_entry_info(options, scan, &de, name, buf));
+            buf.pop();
+        }
+        Ok(())
+    })
+}
+
+/// The info of the entry `de` at `path`, with its sizes unless in a dry run
+fn get_entry_info(
+    options: &Options,
+    scan: &ScanState,
+    de: &DirEntry,
+    name: OsString,
+    path: &Path,
+) -> Result<Info> {
+    // neither of these follow symlinks
+    match options.dry_run {
+        true => Ok(Info::new(name, FileKind::from(de.file_type()?), 0)),
+        false => {
+            scan.counters.add_stat_call();
+            Ok(get_info(name, path, &de.metadata()?, options))
+        }
+    }
+}
+
+/// The directory being read, shared by each of its entries
+struct Dir {
+    parent: NodeId,
+    depth: usize,
+    /// the device the directory is on, if known and needed
+    dev: Option<u64>,
+    /// the rules of the ignore files applying to its entries
+    ignores: Option<Arc<Ignores>>,
+}
+
+/// Makes an entry of the `info` read at `path`, which is `None` if it was filtered out
+fn read_entry(
+    options: &Options,
+    scan: &ScanState,
+    dir: &Dir,
+    mut info: Info,
+    path: &Path,
+) -> Option<Entry> {
+    match options.symlinks {
+        SymlinkPolicy::Record => {}
+        SymlinkPolicy::Skip if info.kind == FileKind::Symlink => return None,
+        SymlinkPolicy::Skip => {}
+        SymlinkPolicy::Follow => follow(options, scan, path, &mut info),
+    }
+    if info.kind == FileKind::File
+        && let Some(classifier) = &options.classifier
+    {
+        info.category = classifier.classify(&info.name);
+    }
+
+    if let Some(filter) = &options.filter
+        && !filter.test(path, &info)
+    {
+        return None;
+    }
+    if let Some(filter) = &options.path_filter
+        && !filter.test(path, dir.depth, &info)
+    {
+        return None;
+    }
+    if let Some(ignores) = &dir.ignores
+        && ignores.ignores(path, info.kind == FileKind::Dir)
+    {
+        return None;
+    }
+
+    // the node is still kept, so the tree shows every link
+    if options.dedup_hardlinks
+        && info.kind != FileKind::Dir
+        && let Some(inode) = info.inode
+        && inode.nlink > 1
+        && !scan.first_link(inode)
+    {
+        info.set_duplicate();
+    }
+
+    // directories on another device are never read, so every directory
+    // read is on the same device as its root
+    let other_dev = |dev| get_dev(path, info.inode).is_some_and(|d| d != dev);
+    let foreign = info.kind == FileKind::Dir && dir.dev.is_some_and(other_dev);
+    // only directories are enqueued, so only they need an owned path
+    let owned = match info.kind {
+        FileKind::Dir if !foreign => path.to_path_buf(),
+        _ => PathBuf::new(),
+    };
+
+    let entry = Entry::new(dir.parent, info, owned, dir.depth);
+    Some(if foreign {
+        entry.without_descent()
+    } else {
+        entry
+    })
+}
+
+/// Replaces a symlink with its target, unless the target was already visited
+///
+/// Directories are all marked as visited, so a link back into the scan is kept
+/// as a [`FileKind::Symlink`], which is never descended into.
+fn follow(options: &Options, scan: &ScanState, path: &Path, info: &mut Info) {
+    match info.kind {
+        FileKind::Dir => {
+            // only a dry run leaves the inode unread
+            let inode = match options.dry_run {
+                true => {
+                    scan.counters.add_stat_call();
+                    std::fs::symlink_metadata(path)
+                        .ok()
+                        .as_ref()
+                        .and_then(get_inode)
+                }
+                false => info.inode,
+            };
+            scan.visit(path, inode);
+        }
+        FileKind::Symlink => {
+            scan.counters.add_stat_call();
+            // a dangling link stays a symlink
+            let Ok(md) = std::fs::metadata(path) else {
+                return;
+            };
+            let kind = FileKind::from(md.file_type());
+            if kind == FileKind::Dir && !scan.visit(path, get_inode(&md)) {
+                return;
+            }
+
+            let name = std::mem::take(&mut info.name);
+            *info = match options.dry_run {
+                true => Info::new(name, kind, 0),
+                false => get_info(name, path, &md, options),
+            };
+        }
+        _ => {}
+    }
+}
+
+/// Reads directories with `getdents64` and `statx`
+///
+/// Each entry is stat'd relative to the directory, asking for only the fields
+/// which the options use, and without building its path first.
+///
+/// This is only built against glibc. musl only has a `statx` wrapper from
+/// 1.2.5, newer than the musl Rust's targets link, and no longer links
+/// `getdents64` by that name since 1.2.4, so musl builds use [`std::fs`].
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod sys {
+    use std::{
+        cell::RefCell,
+        ffi::{CStr, OsStr, c_char, c_int, c_uint},
+        fs::File,
+        io,
+        os::{
+            fd::AsRawFd,
+            unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+        },
+        path::Path,
+        sync::OnceLock,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{Found, PATH, ReadError, ScanState, read_std, set_sizes};
+    use crate::du::{FileKind, Info, Inode, Options, Owner, ScanOp};
+
+    unsafe extern "C" {
+        fn getdents64(fd: c_int, buf: *mut u8, len: usize) -> isize;
+        fn statx(
+            dirfd: c_int,
+            path: *const c_char,
+            flags: c_int,
+            mask: c_uint,
+            buf: *mut Statx,
+        ) -> c_int;
+    }
+
+    /// whether the architecture numbers its `open` flags like Arm, rather than like x86
+    const ARM_FLAGS: bool = cfg!(any(
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "m68k",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+    ));
+    const O_DIRECTORY: c_int = if ARM_FLAGS { 0x4000 } else { 0x10000 };
+    const O_NOFOLLOW: c_int = if ARM_FLAGS { 0x8000 } else { 0x20000 };
+    const O_CLOEXEC: c_int = match cfg!(any(target_arch = "sparc", target_arch = "sparc64")) {
+        true => 0x40_0000,
+        false => 0x8_0000,
+    };
+
+    const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+    const AT_EMPTY_PATH: c_int = 0x1000;
+
+    const STATX_TYPE: c_uint = 0x1;
+    const STATX_NLINK: c_uint = 0x4;
+    const STATX_UID: c_uint = 0x8;
+    const STATX_GID: c_uint = 0x10;
+    const STATX_ATIME: c_uint = 0x20;
+    const STATX_MTIME: c_uint = 0x40;
+    const STATX_INO: c_uint = 0x100;
+    const STATX_SIZE: c_uint = 0x200;
+    const STATX_BLOCKS: c_uint = 0x400;
+
+    const S_IFMT: u16 = 0o170000;
+    const S_IFDIR: u16 = 0o040000;
+    const S_IFREG: u16 = 0o100000;
+    const S_IFLNK: u16 = 0o120000;
+
+    const DT_UNKNOWN: u8 = 0;
+    const DT_DIR: u8 = 4;
+    const DT_REG: u8 = 8;
+    const DT_LNK: u8 = 10;
+
+    const ENOSYS: i32 = 38;
+    const EPERM: i32 = 1;
+
+    /// the size of an entry before its name: its inode, offset, length and type
+    const HEADER: usize = 19;
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct Timestamp {
+        sec: i64,
+        nsec: u32,
+        _reserved: i32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Statx {
+        mask: u32,
+        blksize: u32,
+        attributes: u64,
+        nlink: u32,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+        _spare0: u16,
+        ino: u64,
+        size: u64,
+        blocks: u64,
+        attributes_mask: u64,
+        atime: Timestamp,
+        btime: Timestamp,
+        ctime: Timestamp,
+        mtime: Timestamp,
+        rdev_major: u32,
+        rdev_minor: u32,
+        dev_major: u32,
+        dev_minor: u32,
+        _spare: [u64; 14],
+    }
+
+    const _: () = assert!(size_of::<Statx>() == 256);
+
+    thread_local! {
+        /// reused to read the entries of each directory into
+        static DIRENTS: RefCell<Vec<u8>> = RefCell::new(vec![0; 32 * 1024]);
+    }
+
+    /// Reads the directory at `path`, which is only opened through a symlink if it may `follow` one
+    ///
+    /// The entry may have been replaced since its type was read, so it's
+    /// opened as nothing but a directory, which also keeps a FIFO from
+    /// blocking the open.
+    pub fn read_dir(
+        options: &Options,
+        scan: &ScanState,
+        path: &Path,
+        follow: bool,
+        found: &mut Found,
+    ) -> Result<(), ReadError> {
+        let flags = O_DIRECTORY | O_CLOEXEC | if follow { 0 } else { O_NOFOLLOW };
+        let dir = (File::options().read(true).custom_flags(flags).open(path))
+            .map_err(|e| (ScanOp::ReadDir, e))?;
+        let fd = dir.as_raw_fd();
+        if !supported(fd) {
+            return read_std(options, scan, path, follow, found);
+        }
+
+        DIRENTS.with_borrow_mut(|dirents| {
+            PATH.with_borrow_mut(|buf| {
+                buf.as_mut_os_string().clear();
+                buf.push(path);
+
+                // a file only fails once it's read, but is still a failure to read the directory
+                let mut op = ScanOp::ReadDir;
+                loop {
+                    // SAFETY: the buffer is valid for writes of its whole length
+                    let len = unsafe { getdents64(fd, dirents.as_mut_ptr(), dirents.len()) };
+                    let len = match usize::try_from(len) {
+                        Ok(0) => return Ok(()),
+                        Ok(len) => len,
+                        Err(_) => match io::Error::last_os_error() {
+                            e if e.kind() == io::ErrorKind::Interrupted => continue,
+                            e => return Err((op, e)),
+                        },
+                    };
+                    op = ScanOp::NextEntry;
+
+                    let mut rest = &dirents[..len];
+                    while rest.len() >= HEADER {
+                        if options.cancel.is_cancelled() {
+                            return Ok(());
+                        }
+                        let len = u16::from_ne_bytes([rest[16], rest[17]]) as usize;
+                        let kind = rest[18];
+                        let name = CStr::from_bytes_until_nul(&rest[HEADER..len]);
+                        rest = &rest[len..];
+
+                        let Ok(name) = name else { continue };
+                        if matches!(name.to_bytes(), b"." | b"..") {
+                            continue;
+                        }
+                        buf.push(OsStr::from_bytes(name.to_bytes()));
+                        found(buf, get_info(options, scan, fd, name, kind, buf));
+                        buf.pop();
+                    }
+                }
+            })
+        })
+    }
+
+    /// Whether `statx` can be called, as older kernels and some sandboxes refuse it
+    fn supported(fd: c_int) -> bool {
+        static SUPPORTED: OnceLock<bool> = OnceLock::new();
+        *SUPPORTED.get_or_init(|| match stat(fd, c"", AT_EMPTY_PATH, 0) {
+            Ok(_) => true,
+            Err(e) => !matches!(e.raw_os_error(), Some(ENOSYS | EPERM)),
+        })
+    }
+
+    fn stat(fd: c_int, name: &CStr, flags: c_int, mask: c_uint) -> io::Result<Statx> {
+        let mut stx = Statx::default();
+        // SAFETY: `name` is nul-terminated and `stx` is large enough for any kernel's fields
+        match unsafe { statx(fd, name.as_ptr(), flags, mask, &mut stx) } {
+            0 => Ok(stx),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// The info of the entry `name` within the directory `fd`, of `kind` if known
+    ///
+    /// A dry run only needs the kind, which is known without a stat call on
+    /// most filesystems. A filesystem may not have every field asked for,
+    /// leaving them out of the mask returned, in which case the entry's
+    /// metadata is read again from its `path` through [`std::fs`].
+    fn get_info(
+        options: &Options,
+        scan: &ScanState,
+        fd: c_int,
+        name: &CStr,
+        kind: u8,
+        path: &Path,
+    ) -> io::Result<Info> {
+        let os_name = OsStr::from_bytes(name.to_bytes());
+        let kind = match kind {
+            _ if !options.dry_run => None,
+            DT_UNKNOWN => None,
+            DT_DIR => Some(FileKind::Dir),
+            DT_REG => Some(FileKind::File),
+            DT_LNK => Some(FileKind::Symlink),
+            _ => Some(FileKind::Other),
+        };
+        if let Some(kind) = kind {
+            return Ok(Info::new(os_name, kind, 0));
+        }
+
+        let mut mask = match options.dry_run {
+            true => STATX_TYPE,
+            false => STATX_TYPE | STATX_NLINK | STATX_INO | STATX_SIZE | STATX_BLOCKS | STATX_MTIME,
+        };
+        if !options.dry_run && options.atime {
+            mask |= STATX_ATIME;
+        }
+        if !options.dry_run && options.owners {
+            mask |= STATX_UID | STATX_GID;
+        }
+        scan.counters.add_stat_call();
+        let stx = stat(fd, name, AT_SYMLINK_NOFOLLOW, mask)?;
+
+        let kind = match stx.mode & S_IFMT {
+            S_IFDIR => FileKind::Dir,
+            S_IFREG => FileKind::File,
+            S_IFLNK => FileKind::Symlink,
+            _ => FileKind::Other,
+        };
+        if options.dry_run {
+            return Ok(Info::new(os_name, kind, 0));
+        }
+        if mask & !stx.mask != 0 {
+            scan.counters.add_stat_call();
+            let md = std::fs::symlink_metadata(path)?;
+            return Ok(super::get_info(os_name, path, &md, options));
+        }
+
+        let mut info = Info::new(os_name, kind, stx.size);
+        info.inode = Some(Inode {
+            dev: makedev(stx.dev_major, stx.dev_minor),
+            ino: stx.ino,
+            nlink: stx.nlink.into(),
+        });
+        info.mtime = get_time(stx.mtime);
+        if options.atime {
+            info.atime = get_time(stx.atime);
+        }
+        if options.owners {
+            info.owner = Some(Owner {
+                uid: stx.uid,
+                gid: stx.gid,
+            });
+        }
+        set_sizes(&mut info, options, stx.size, stx.blocks * 512);
+        Ok(info)
+    }
+
+    /// The device number of `major` and `minor`, encoded the way glibc does
+    fn makedev(major: u32, minor: u32) -> u64 {
+        let (major, minor) = (u64::from(major), u64::from(minor));
+        (major & 0xffff_f000) << 32
+            | (major & 0xfff) << 8
+            | (minor & 0xffff_ff00) << 12
+            | minor & 0xff
+    }
+
+    fn get_time(time: Timestamp) -> Option<SystemTime> {
+        let nanos = Duration::from_nanos(time.nsec.into());
+        match u64::try_from(time.sec) {
+            Ok(sec) => UNIX_EPOCH.checked_add(Duration::from_secs(sec) + nanos),
+            Err(_) => (UNIX_EPOCH.checked_sub(Duration::from_secs(time.sec.unsigned_abs())))?
+                .checked_add(nanos),
+        }
+    }
+}
+
+/// Reads directories with `FindFirstFileExW`, which gives each entry's metadata along with it
+///
+/// Only the allocated size of a file needs a call of its own, which is only
+/// made in [`SizeMode::Allocated`].
+#[cfg(windows)]
+mod sys {
+    use std::{
+        ffi::{OsString, c_void},
+        io,
+        os::windows::ffi::{OsStrExt, OsStringExt},
+        path::Path,
+        ptr::null_mut,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{Found, PATH, ReadError, ScanState, get_compressed_size, set_sizes};
+    use crate::du::{FileKind, Info, Options, ScanOp, SizeMode};
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+
+    #[repr(C)]
+    struct FindData {
+        attributes: u32,
+        creation_time: FileTime,
+        access_time: FileTime,
+        write_time: FileTime,
+        size_high: u32,
+        size_low: u32,
+        reserved0: u32,
+        reserved1: u32,
+        name: [u16; 260],
+        alternate_name: [u16; 14],
+    }
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn FindFirstFileExW(
+            name: *const u16,
+            info_level: i32,
+            data: *mut FindData,
+            search_op: i32,
+            filter: *mut c_void,
+            flags: u32,
+        ) -> *mut c_void;
+        fn FindNextFileW(find: *mut c_void, data: *mut FindData) -> i32;
+        fn FindClose(find: *mut c_void) -> i32;
+    }
+
+    /// leaves out the short names of entries, which are never used
+    const FIND_EX_INFO_BASIC: i32 = 1;
+    const FIND_EX_SEARCH_NAME_MATCH: i32 = 0;
+    const FIND_FIRST_EX_LARGE_FETCH: u32 = 2;
+    const INVALID_HANDLE_VALUE: *mut c_void = -1isize as *mut c_void;
+
+    const ERROR_FILE_NOT_FOUND: i32 = 2;
+    const ERROR_NO_MORE_FILES: i32 = 18;
+
+    const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+    const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+    /// set in the tags of reparse points which stand for another entry, as std's symlinks do
+    const NAME_SURROGATE: u32 = 0x2000_0000;
+
+    /// the time between 1601, when file times begin, and the Unix epoch, in 100ns intervals
+    const EPOCH_OFFSET: u64 = 116_444_736_000_000_000;
+
+    /// Closes a search once dropped
+    struct Find(*mut c_void);
+
+    impl Drop for Find {
+        fn drop(&mut self) {
+            // SAFETY: the handle is valid, and closed only here
+            unsafe { FindClose(self.0) };
+        }
+    }
+
+    pub fn read_dir(
+        options: &Options,
+        _scan: &ScanState,
+        path: &Path,
+        _follow: bool,
+        found: &mut Found,
+    ) -> Result<(), ReadError> {
+        let pattern = search_pattern(path);
+        // SAFETY: zeroed data is valid, as it's only integers
+        let mut data: FindData = unsafe { std::mem::zeroed() };
+        // SAFETY: `pattern` is nul-terminated and `data` is a valid out pointer
+        let find = unsafe {
+            FindFirstFileExW(
+                pattern.as_ptr(),
+                FIND_EX_INFO_BASIC,
+                &mut data,
+                FIND_EX_SEARCH_NAME_MATCH,
+                null_mut(),
+                FIND_FIRST_EX_LARGE_FETCH,
+            )
+        };
+        if find == INVALID_HANDLE_VALUE {
+            return match io::Error::last_os_error() {
+                // only the root of a drive can be without even `.`
+                e if e.raw_os_error() == Some(ERROR_FILE_NOT_FOUND) => Ok(()),
+                e => Err((ScanOp::ReadDir, e)),
+            };
+        }
+        let find = Find(find);
+
+        PATH.with_borrow_mut(|buf| {
+            buf.as_mut_os_string().clear();
+            buf.push(path);
+
+            loop {
+                if options.cancel.is_cancelled() {
+                    return Ok(());
+                }
+                let len = data
+                    .name
+                    .iter()
+                    .position(|&c| c == 0)
+                    .unwrap_or(data.name.len());
+                let name = OsString::from_wide(&data.name[..len]);
+                if name != "." && name != ".." {
+                    buf.push(&name);
+                    found(buf, Ok(get_info(options, &data, name, buf)));
+                    buf.pop();
+                }
+
+                // SAFETY: the handle is valid and `data` is a valid out pointer
+                if unsafe { FindNextFileW(find.0, &mut data) } == 0 {
+                    return match io::Error::last_os_error() {
+                        e if e.raw_os_error() == Some(ERROR_NO_MORE_FILES) => Ok(()),
+                        e => Err((ScanOp::NextEntry, e)),
+                    };
+                }
+            }
+        })
+    }
+
+    /// The pattern matching every entry of `path`, as an extended-length path
+    ///
+    /// Such paths aren't limited to `MAX_PATH`, but are never normalized, so
+    /// `path` is made absolute first.
+    fn search_pattern(path: &Path) -> Vec<u16> {
+        const SEP: u16 = b'\\' as u16;
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let wide: Vec<u16> = path.as_os_str().encode_wide().collect();
+
+        let mut pattern: Vec<u16> = match wide.as_slice() {
+            // already verbatim, or a device path
+            [SEP, SEP, q, SEP, ..] if [b'?', b'.'].map(u16::from).contains(q) => wide,
+            // `\\server\share` is `\\?\UNC\server\share`
+            [SEP, SEP, rest @ ..] => (r"\\?\UNC\".encode_utf16())
+                .chain(rest.iter().copied())
+                .collect(),
+            _ => (r"\\?\".encode_utf16()).chain(wide).collect(),
+        };
+        if pattern.last() != Some(&SEP) {
+            pattern.push(SEP);
+        }
+        pattern.extend([u16::from(b'*'), 0]);
+        pattern
+    }
+
+    /// The info of an entry found at `path`, which like std's never follows symlinks
+    fn get_info(options: &Options, data: &FindData, name: OsString, path: &Path) -> Info {
+        let reparse = data.attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0;
+        let kind = FileKind::classify(
+            reparse && data.reserved0 & NAME_SURROGATE != 0,
+            data.attributes & FILE_ATTRIBUTE_DIRECTORY != 0,
+            true,
+        );
+        if options.dry_run {
+            return Info::new(name, kind, 0);
+        }
+
+        let len = u64::from(data.size_high) << 32 | u64::from(data.size_low);
+        let mut info = Info::new(name, kind, len);
+        info.mtime = get_time(data.write_time);
+        if options.atime {
+            info.atime = get_time(data.access_time);
+        }
+        let alloc = match options.size_mode == SizeMode::Allocated && kind == FileKind::File {
+            true => get_compressed_size(path).unwrap_or(len),
+            false => len,
+        };
+        set_sizes(&mut info, options, len, alloc);
+        info
+    }
+
+    fn get_time(time: FileTime) -> Option<SystemTime> {
+        let ticks = u64::from(time.high) << 32 | u64::from(time.low);
+        match ticks.checked_sub(EPOCH_OFFSET) {
+            Some(since) => UNIX_EPOCH.checked_add(Duration::from_nanos(since) * 100),
+            None => UNIX_EPOCH.checked_sub(Duration::from_nanos(EPOCH_OFFSET - ticks) * 100),
+        }
+    }
+}
+
+#[cfg(not(any(all(target_os = "linux", target_env = "gnu"), windows)))]
+mod sys {
+    pub use super::read_std as read_dir;
+}
+
+/// Helpers for tests, which scan trees made for them
+#[cfg(test)]
+pub(crate) mod test {
+    use std::path::{Path, PathBuf};
+
+    use crate::du::{Du, st};
+
+    /// A directory for a test to build a tree in, removed once dropped
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// Makes an empty directory, unique to the test `name`
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("dustat-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+
+        /// Makes a file of `len` bytes at `rel`, along with its parents
+        pub fn file(&self, rel: &str, len: usize) -> PathBuf {
+            let path = self.0.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, vec![b'x'; len]).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Makes `dirs` directories of `files` files each, nested two deep
+    pub fn tree(name: &str, dirs: usize, files: usize) -> TempDir {
+        let dir = TempDir::new(name);
+        for d in 0..dirs {
+            for f in 0..files {
+                dir.file(&format!("{}/{d}/{f}", d % 4), f);
+            }
+        }
+        dir
+    }
+
+    /// Scans `path` to the end on one thread, once `configure` has set its options
+    pub fn scan(path: &Path, configure: impl FnOnce(&mut Du<st::Source>)) -> Du<st::Source> {
+        let mut du = Du::new(st::Source::default());
+        configure(&mut du);
+        du.begin(path);
+        du.read(&mut |_, _| true);
+        du
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[test]
+    fn names_which_arent_utf8_are_kept() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        use super::test::{TempDir, scan};
+
+        let dir = TempDir::new("util-bad-name");
+        dir.file("good", 10);
+        let name = OsStr::from_bytes(b"bad \xff name");
+        std::fs::write(dir.path().join(name), [0; 20]).unwrap();
+
+        let du = scan(dir.path(), |_| {});
+        let stats = du.stats();
+        assert!(du.errors().is_empty());
+        assert_eq!(stats.head().info().files, 2);
+        assert!(stats.find(&dir.path().join(name)).is_some());
+        assert!(stats.find(&dir.path().join("good")).is_some());
+    }
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    #[test]
+    fn statx_reads_what_std_does() {
+        use std::{collections::BTreeMap, io, path::Path};
+
+        use super::{Found, ReadError, ScanState, read_std, sys, test::TempDir};
+        use crate::du::{Info, Options};
+
+        let dir = TempDir::new("util-statx");
+        dir.file("f", 5000);
+        dir.file("d/g", 10);
+        std::os::unix::fs::symlink("f", dir.path().join("l")).unwrap();
+        std::fs::hard_link(dir.path().join("f"), dir.path().join("h")).unwrap();
+
+        let options = Options {
+            atime: true,
+            owners: true,
+            ..Options::default()
+        };
+        type ReadDir = fn(&Options, &ScanState, &Path, bool, &mut Found) -> Result<(), ReadError>;
+        let read = |read_dir: ReadDir| {
+            let mut infos = BTreeMap::new();
+            let mut found = |path: &Path, info: io::Result<Info>| {
+                infos.insert(path.to_path_buf(), format!("{:?}", info.unwrap()));
+            };
+            read_dir(&options, &ScanState::new(), dir.path(), false, &mut found).unwrap();
+            infos
         };
+        let fast = read(sys::read_dir);
+        assert_eq!(fast.len(), 4);
+        assert_eq!(fast, read(read_std));
     }
 
-    for value in handle!(std::fs::read_dir(path)) {
-        let de = handle!(value);
-        let md = handle!(de.metadata());
-        let path = de.path();
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn entries_which_fail_are_skipped_alone() {
+        use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
 
-        // TODO: consider just ignoring invalid file names.
-        let name = handle!(get_name(&path));
-        let info = Info::new(name, FileKind::from(md.file_type()), md.len());
+        use super::test::{TempDir, scan};
+        use crate::du::{NodeId, ScanOp};
+
+        const FILES: usize = 20;
+        let dir = TempDir::new("util-skip-entry");
+        for i in 0..FILES {
+            dir.file(&format!("{i}"), 10);
+        }
+
+        // the first entry read removes every other, whose names were
+        // already listed, so each of them then fails to be read
+        let removed = AtomicBool::new(false);
+        let root = dir.path().to_path_buf();
+        let du = scan(dir.path(), move |du| {
+            du.filter(move |path, _| {
+                if !removed.swap(true, Relaxed) {
+                    for i in 0..FILES {
+                        let other = root.join(format!("{i}"));
+                        if other != path {
+                            std::fs::remove_file(other).unwrap();
+                        }
+                    }
+                }
+                true
+            })
+        });
 
-        entry(Entry::new(parent, info, path));
+        let stats = du.stats();
+        assert_eq!(stats.head().info().files, 1);
+        assert_eq!(du.errors().len(), FILES - 1);
+        for error in du.errors() {
+            assert_eq!(error.op, ScanOp::Metadata);
+            assert_eq!(error.node, NodeId::ROOT);
+            assert_eq!(error.path.parent(), Some(dir.path()));
+            assert!(stats.find(&error.path).is_none());
+        }
     }
 }